@@ -0,0 +1,135 @@
+// Arpeggiator plays the tones of a held chord one after another instead of all at once,
+// and the step sequencer grid writes rhythmic parts as steps, "c3 x...x...x.x.x...".
+
+// settings, global or per notesheet:
+// arp: off (default), up, down, updown or random
+// arp_rate: length of a step, 1/16 by default
+// arp_octaves: how many octaves the arpeggio goes through, 1 by default
+// grid_step: length of a step of the grids, 1/16 by default
+
+// grid steps: x is a hit, X is an accented hit, - holds the previous hit, . is a rest
+
+use crate::harmonics::Tone;
+use crate::math::Rng;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArpMode {
+    Off,
+    Up,
+    Down,
+    // up and back down, without playing the highest and the lowest tones twice
+    UpDown,
+    Random,
+}
+
+#[derive(Clone)]
+pub struct Arpeggio {
+    pub mode: ArpMode,
+    // length of a step as a fraction of a whole note
+    pub rate: (i64, i64),
+    pub octaves: i64,
+    // length of a grid step as a fraction of a whole note, grids keep it when the arpeggio rate changes
+    pub grid: (i64, i64),
+}
+
+impl Default for Arpeggio {
+    fn default() -> Arpeggio {
+        Arpeggio {
+            mode: ArpMode::Off,
+            rate: (1, 16),
+            octaves: 1,
+            grid: (1, 16),
+        }
+    }
+}
+
+fn octave_up(tone: &Tone, octaves: i64) -> Tone {
+    match tone {
+        Tone::Semitones(semitones) => Tone::Semitones(semitones + 12. * octaves as f64),
+        Tone::Hz(freq) => Tone::Hz(freq * (octaves as f64).exp2()),
+    }
+}
+
+// "1/16" as (1, 16)
+fn parse_step(value: &str) -> Option<(i64, i64)> {
+    match value.split_once('/').map(|(num, den)| (num.parse::<i64>(), den.parse::<i64>())) {
+        Some((Ok(num), Ok(den))) if num > 0 && den > 0 => Some((num, den)),
+        _ => None,
+    }
+}
+
+impl Arpeggio {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        let value = param.1.trim();
+        match param.0.as_str() {
+            "arp" => {
+                self.mode = match value {
+                    "off" | "none" => ArpMode::Off,
+                    "up" => ArpMode::Up,
+                    "down" => ArpMode::Down,
+                    "updown" => ArpMode::UpDown,
+                    "random" => ArpMode::Random,
+                    _ => return Err("unknown arp, try off, up, down, updown or random"),
+                }
+            }
+            "arp_rate" => {
+                self.rate = match parse_step(value) {
+                    Some(rate) => rate,
+                    None => return Err("arp_rate should be a fraction, such as 1/16"),
+                }
+            }
+            "arp_octaves" => {
+                self.octaves = match value.parse::<i64>() {
+                    Ok(val) if val > 0 => val,
+                    _ => return Err("arp_octaves should be a positive whole number"),
+                }
+            }
+            "grid_step" => {
+                self.grid = match parse_step(value) {
+                    Some(step) => step,
+                    None => return Err("grid_step should be a fraction, such as 1/16"),
+                }
+            }
+            _ => return Err("setting an unexisting parameter"),
+        }
+        Ok(())
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.mode != ArpMode::Off
+    }
+
+    // length of a step in 1/4th
+    pub fn step(&self) -> f64 {
+        4. * self.rate.0 as f64 / self.rate.1 as f64
+    }
+
+    // length of a grid step in 1/4th
+    pub fn grid_step(&self) -> f64 {
+        4. * self.grid.0 as f64 / self.grid.1 as f64
+    }
+
+    // tone of every step, seed makes random arpeggios differ from one chord to another
+    pub fn steps(&self, tones: &[Tone], count: usize, seed: u64) -> Vec<Tone> {
+        let mut sorted: Vec<Tone> = tones.to_vec();
+        sorted.sort_by(|a, b| a.semitones().total_cmp(&b.semitones()));
+        let ladder: Vec<Tone> = (0..self.octaves)
+            .flat_map(|octave| sorted.iter().map(move |tone| octave_up(tone, octave)))
+            .collect();
+        let pattern: Vec<Tone> = match self.mode {
+            ArpMode::Down => ladder.into_iter().rev().collect(),
+            ArpMode::UpDown if ladder.len() > 2 => {
+                let down: Vec<Tone> = ladder[1..ladder.len() - 1].iter().rev().cloned().collect();
+                [ladder, down].concat()
+            }
+            ArpMode::Random => {
+                let mut rng = Rng::new(seed);
+                return (0..count)
+                    .map(|_| ladder[(rng.next_u64() % ladder.len() as u64) as usize])
+                    .collect();
+            }
+            _ => ladder,
+        };
+        (0..count).map(|i| pattern[i % pattern.len()]).collect()
+    }
+}
@@ -0,0 +1,263 @@
+// Sections keep the lines of a part of the song, so that it can be played again without writing it twice.
+// Bar numbers inside a section start at 1, the arrangement places the sections one after another
+// and shifts their bar numbers, so that the parser sees the bars where they are played.
+
+// section verse: 4 bars
+// Notesheet: SineWave
+// 1, c4 0 1/4, ...
+// volta 1:
+// 4, c4 0 1
+// end volta
+// volta 2 3:
+// 4, g4 0 1
+// end volta
+// record
+// end section
+//
+// Arrange: intro verse x2 chorus verse chorus[transpose: 2] outro
+//
+// Volta bars are only played on the listed passes of the section, passes are counted along the arrangement.
+// A pass is as long as the highest bar it plays, or as the length written after the name if it is longer,
+// so that bars of rest at the end of the section don't have to be written.
+// Bar lines, "At bar N:" lines and the bars of crescendo, diminuendo, accelerando, ritardando
+// and automation lanes are shifted.
+// Settings in brackets are added to every notesheet line of the section, see transform.rs.
+
+use crate::source::Line;
+use std::collections::HashMap;
+
+// keys of the settings that are given as "bar value -> bar value"
+const RAMPS: [&str; 4] = ["crescendo", "diminuendo", "accelerando", "ritardando"];
+
+pub struct Section<'a> {
+    // raw lines with their places
+    lines: Vec<(Line<'a>, String)>,
+    // written length in bars
+    length: Option<i64>,
+}
+
+// bar number of a bar line, they start with the number followed by a comma
+pub fn bar_number(line: &str) -> Option<i64> {
+    line.find(',').and_then(|comma| line[..comma].trim().parse::<i64>().ok())
+}
+
+// passes of a "volta 1 2:" line
+fn parse_volta(line: &str, counter: Line) -> Option<Vec<usize>> {
+    let passes = line.strip_prefix("volta")?.strip_suffix(':')?;
+    let passes: Result<Vec<usize>, _> = passes
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|pass| !pass.is_empty())
+        .map(|pass| pass.parse::<usize>())
+        .collect();
+    match passes {
+        Ok(passes) if !passes.is_empty() => Some(passes),
+        _ => panic!("wavg synthax error: volta should list the passes it is played on, such as \"volta 1 2:\" at line {counter}"),
+    }
+}
+
+// "9 p -> 12 ff" with its bars moved
+fn shift_ramp(value: &str, offset: i64, counter: Line) -> String {
+    value
+        .split("->")
+        .map(|end| {
+            let end = end.trim();
+            match end.split_once(char::is_whitespace) {
+                Some((bar, rest)) => match bar.parse::<i64>() {
+                    Ok(bar) => format!("{} {}", bar + offset, rest.trim()),
+                    Err(_) => panic!("wavg synthax error: incorrect bar number in a gradual change at line {counter}"),
+                },
+                None => panic!("wavg synthax error: gradual change ends should look like \"bar value\" at line {counter}"),
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" -> ")
+}
+
+// "volume bar 1 0 -> bar 8 1" with its bars moved
+fn shift_lane(value: &str, offset: i64, counter: Line) -> String {
+    let words: Vec<&str> = value.split_whitespace().collect();
+    words
+        .iter()
+        .enumerate()
+        .map(|(i, word)| match i.checked_sub(1).map(|previous| words[previous].to_ascii_lowercase()) {
+            Some(previous) if previous == "bar" => match word.parse::<i64>() {
+                Ok(bar) => (bar + offset).to_string(),
+                Err(_) => panic!("wavg synthax error: incorrect bar number in an automation lane at line {counter}"),
+            },
+            _ => word.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+// the same line, played offset bars later
+fn shift_line(raw: &str, offset: i64, counter: Line) -> String {
+    let line = raw.to_ascii_lowercase();
+    if let Some(bar) = bar_number(&line) {
+        let comma = line.find(',').unwrap();
+        return format!("{}{}", bar + offset, &raw[comma..]);
+    }
+    if let Some(colon) = line.find(':') {
+        if let Some(bar) = line[..colon].strip_prefix("at bar") {
+            return match bar.trim().parse::<i64>() {
+                Ok(bar) => format!("At bar {}{}", bar + offset, &raw[colon..]),
+                Err(_) => panic!("wavg synthax error: incorrect bar number at line {counter}"),
+            };
+        }
+        // bars of gradual changes and automation, the other settings stay as they are
+        return raw
+            .split(',')
+            .map(|entry| match entry.split_once(':') {
+                Some((key, value)) if key.trim().eq_ignore_ascii_case("automate") => {
+                    format!("{key}: {}", shift_lane(value, offset, counter))
+                }
+                Some((key, value)) if !line.starts_with("notesheet") && RAMPS.contains(&key.trim().to_ascii_lowercase().as_str()) => {
+                    format!("{key}: {}", shift_ramp(value, offset, counter))
+                }
+                _ => entry.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(",");
+    }
+    raw.to_string()
+}
+
+impl<'a> Section<'a> {
+    pub fn new(length: Option<i64>) -> Section<'a> {
+        Section {
+            lines: Vec::new(),
+            length,
+        }
+    }
+
+    pub fn push(&mut self, counter: Line<'a>, raw: String) {
+        self.lines.push((counter, raw));
+    }
+
+    // lines of the given pass (starting at 1), without the volta lines and the voltas of the other passes
+    fn pass(&self, pass: usize) -> Vec<&(Line<'a>, String)> {
+        let mut played: Vec<&(Line<'a>, String)> = Vec::with_capacity(self.lines.len());
+        // passes of the volta the lines are in
+        let mut volta: Option<Vec<usize>> = None;
+        for entry in &self.lines {
+            let (counter, raw) = entry;
+            let line = raw.to_ascii_lowercase();
+            if let Some(passes) = parse_volta(&line, *counter) {
+                if volta.is_some() {
+                    panic!("wavg synthax error: volta starts before the previous one ends at line {counter}");
+                }
+                volta = Some(passes);
+                continue;
+            }
+            if line == "end volta" {
+                if volta.take().is_none() {
+                    panic!("wavg synthax error: volta ends before it starts at line {counter}");
+                }
+                continue;
+            }
+            if volta.as_ref().is_some_and(|passes| !passes.contains(&pass)) {
+                continue;
+            }
+            played.push(entry);
+        }
+        if volta.is_some() {
+            panic!("wavg synthax error: volta of the section is not ended with \"end volta\"");
+        }
+        played
+    }
+
+    // length in bars of the given pass
+    pub fn bars(&self, pass: usize) -> i64 {
+        let highest = self.pass(pass).iter().filter_map(|(_, raw)| bar_number(raw)).max().unwrap_or(0);
+        highest.max(self.length.unwrap_or(0))
+    }
+
+    // lines of the given pass, with the bars moved offset bars later
+    pub fn play(&self, pass: usize, offset: i64) -> Vec<(Line<'a>, String)> {
+        self.pass(pass)
+            .into_iter()
+            .map(|(counter, raw)| (*counter, shift_line(raw, offset, *counter)))
+            .collect()
+    }
+}
+
+// words of the arrangement, the brackets are kept with their section name
+fn split_words(s: &str) -> Vec<&str> {
+    let mut words: Vec<&str> = Vec::new();
+    let mut depth: i64 = 0;
+    let mut start: Option<usize> = None;
+    for (i, c) in s.char_indices() {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            c if c.is_whitespace() && depth == 0 => {
+                if let Some(begin) = start.take() {
+                    words.push(&s[begin..i]);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        start.get_or_insert(i);
+    }
+    if let Some(begin) = start {
+        words.push(&s[begin..]);
+    }
+    words
+}
+
+// "intro verse x2 chorus[transpose: 2]" as (section name, settings added to its notesheets),
+// repetitions are written right after the name
+pub fn parse_arrangement(s: &str, counter: Line) -> Vec<(String, String)> {
+    let mut order: Vec<(String, String)> = Vec::new();
+    for word in split_words(s) {
+        let times = word.strip_prefix('x').and_then(|times| times.parse::<usize>().ok());
+        match (times, order.last().cloned()) {
+            (Some(times), Some(item)) if times > 0 => {
+                for _ in 1..times {
+                    order.push(item.clone());
+                }
+            }
+            (Some(_), _) => panic!("wavg synthax error: repeat count {word} should follow a section name at line {counter}"),
+            (None, _) => match word.split_once('[') {
+                Some((name, settings)) => match settings.strip_suffix(']') {
+                    Some(settings) => order.push((name.to_string(), settings.trim().to_string())),
+                    None => panic!("wavg synthax error: unbalanced brackets in the arrangement at line {counter}"),
+                },
+                None => order.push((word.to_string(), String::new())),
+            },
+        }
+    }
+    order
+}
+
+// notesheet line with the settings of the arrangement added at its end, so that they replace the written ones
+fn with_settings(raw: String, settings: &str) -> String {
+    if settings.is_empty() || !raw.to_ascii_lowercase().starts_with("notesheet") {
+        return raw;
+    }
+    format!("{raw}, {settings}")
+}
+
+// lines of the whole arrangement, the first section starts at the given bar
+pub fn arrange<'a>(sections: &HashMap<String, Section<'a>>, order: &[(String, String)], first_bar: i64, counter: Line) -> Vec<(Line<'a>, String)> {
+    let mut lines: Vec<(Line<'a>, String)> = Vec::new();
+    let mut passes: HashMap<&str, usize> = HashMap::new();
+    let mut offset = first_bar - 1;
+    for (name, settings) in order {
+        let section = match sections.get(name) {
+            Some(section) => section,
+            None => panic!("wavg synthax error: section {name} is not defined before line {counter}"),
+        };
+        let pass = passes.entry(name).or_insert(0);
+        *pass += 1;
+        lines.extend(
+            section
+                .play(*pass, offset)
+                .into_iter()
+                .map(|(line, raw)| (line, with_settings(raw, settings))),
+        );
+        offset += section.bars(*pass);
+    }
+    lines
+}
@@ -0,0 +1,170 @@
+// Automation lanes change a parameter over the song, from bar to bar:
+// automate: volume bar 1 0.0 -> bar 8 1.0 curve exp
+// goes from 0 at the beginning of bar 1 to 1 at the beginning of bar 8, the value holds before the first
+// and after the last point, there can be more points: "bar 1 0 -> bar 5 1 -> bar 9 0.5".
+
+// On a notesheet line the lane changes that notesheet: volume is the gain of its whole track, which changes
+// at every sample, any other numeric parameter of the instrument is set at the start of every note
+// (the modulation matrix is added on top, see modulation.rs). On a line of its own the lane changes the master:
+// volume is the gain of the whole song, any other parameter is automated on every notesheet whose instrument
+// has it, unless the notesheet has a lane of its own. A later lane of the same parameter replaces the earlier one.
+
+// curves: lin (default), exp (slow start, fast end), log (fast start, slow end), smooth (slow at both ends)
+
+use crate::tempo::TempoMap;
+use crate::track::{Gain, Track};
+
+#[derive(Clone, Copy)]
+enum Curve {
+    Linear,
+    Exponential,
+    Logarithmic,
+    Smooth,
+}
+
+impl Curve {
+    // progress along a segment, x and the result are within [0, 1]
+    fn shape(&self, x: f64) -> f64 {
+        // steepness of exp and log
+        let k: f64 = 4.;
+        match self {
+            Curve::Linear => x,
+            Curve::Exponential => ((k * x).exp() - 1.) / (k.exp() - 1.),
+            Curve::Logarithmic => 1. - ((k * (1. - x)).exp() - 1.) / (k.exp() - 1.),
+            Curve::Smooth => x * x * (3. - 2. * x),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Lane {
+    pub parameter: String,
+    // (bar, value) sorted by bar
+    points: Vec<(i64, f64)>,
+    curve: Curve,
+}
+
+impl Lane {
+    // "volume bar 1 0.0 -> bar 8 1.0 curve exp"
+    fn parse(value: &str) -> Result<Lane, &'static str> {
+        let (parameter, rest) = match value.trim().split_once(char::is_whitespace) {
+            Some(split) => split,
+            None => return Err("automate should be a parameter and its points, such as \"volume bar 1 0 -> bar 8 1\""),
+        };
+        // the curve is the word after a "curve" keyword at the end
+        let words: Vec<&str> = rest.split_whitespace().collect();
+        let (words, curve) = match words.iter().position(|word| *word == "curve") {
+            Some(at) => (
+                &words[..at],
+                match words[at + 1..] {
+                    ["lin" | "linear"] => Curve::Linear,
+                    ["exp"] => Curve::Exponential,
+                    ["log"] => Curve::Logarithmic,
+                    ["smooth"] => Curve::Smooth,
+                    _ => return Err("unknown automation curve, try lin, exp, log or smooth"),
+                },
+            ),
+            None => (&words[..], Curve::Linear),
+        };
+        let mut points: Vec<(i64, f64)> = Vec::new();
+        for point in words.join(" ").split("->") {
+            match point.split_whitespace().collect::<Vec<&str>>()[..] {
+                ["bar", bar, value] => match (bar.parse::<i64>(), value.parse::<f64>()) {
+                    (Ok(bar), Ok(value)) if bar > 0 => points.push((bar, value)),
+                    _ => return Err("automation points should be a bar number and a value, such as \"bar 8 1.0\""),
+                },
+                _ => return Err("automation points look like \"bar 8 1.0\" and are separated with ->"),
+            }
+        }
+        if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+            return Err("automation points should go from earlier bars to later ones");
+        }
+        Ok(Lane {
+            parameter: parameter.to_string(),
+            points,
+            curve,
+        })
+    }
+
+    // (seconds since the beginning of the song, value) points
+    fn timed(&self, tempo: &TempoMap) -> Vec<(f64, f64)> {
+        self.points
+            .iter()
+            .map(|(bar, value)| (tempo.seconds(tempo.bar_position(*bar)), *value))
+            .collect()
+    }
+
+    fn interpolate(&self, timed: &[(f64, f64)], time: f64) -> f64 {
+        let mut from = timed[0];
+        for &(until, value) in timed {
+            if time < until {
+                if until <= from.0 {
+                    return from.1;
+                }
+                return from.1 + (value - from.1) * self.curve.shape((time - from.0) / (until - from.0));
+            }
+            from = (until, value);
+        }
+        from.1
+    }
+
+    // value at this many seconds since the beginning of the song
+    pub fn value(&self, time: f64, tempo: &TempoMap) -> f64 {
+        self.interpolate(&self.timed(tempo), time)
+    }
+
+    // gain of every sample until the end of the track
+    fn gain(&self, tempo: &TempoMap, samples: usize) -> Gain {
+        let timed = self.timed(tempo);
+        Gain::Curve(
+            (0..samples)
+                .map(|i| self.interpolate(&timed, Track::sample_index_to_time(i)))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Automation {
+    lanes: Vec<Lane>,
+}
+
+impl Automation {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        if param.0 != "automate" {
+            return Err("setting an unexisting parameter");
+        }
+        let lane = Lane::parse(&param.1)?;
+        self.lanes.retain(|other| other.parameter != lane.parameter);
+        self.lanes.push(lane);
+        Ok(())
+    }
+
+    // lanes of the parameters of the instrument, which are set note by note
+    pub fn instrument_lanes(&self) -> impl Iterator<Item = &Lane> {
+        self.lanes.iter().filter(|lane| lane.parameter != "volume")
+    }
+
+    // lanes of this notesheet, with the lanes of the master for the parameters that the instrument has
+    // and that are not automated here
+    pub fn with_master(&self, master: &Automation, has: impl Fn(&str) -> bool) -> Automation {
+        let mut lanes = self.lanes.clone();
+        for lane in master.instrument_lanes() {
+            if has(&lane.parameter) && !self.lanes.iter().any(|own| own.parameter == lane.parameter) {
+                lanes.push(lane.clone());
+            }
+        }
+        Automation { lanes }
+    }
+
+    pub fn only_volume(&self) -> bool {
+        self.instrument_lanes().next().is_none()
+    }
+
+    // the volume lane becomes the gain of the track
+    pub fn apply_volume(&self, track: &mut Track, tempo: &TempoMap) {
+        if let Some(lane) = self.lanes.iter().find(|lane| lane.parameter == "volume") {
+            track.loudness = lane.gain(tempo, track.ending_sample_index());
+        }
+    }
+}
@@ -0,0 +1,256 @@
+// Chord symbols, such as Cmaj7, Am/E or G7b9, and the way they are voiced.
+// Symbols are case sensitive (M7 is a major seventh, m7 is a minor one) and always start
+// with an uppercase root, which tells them apart from the notes, that are written in lowercase.
+// A root followed by a number only, such as C4 or G7, is read as a note. Symbols in quotes are always chords,
+// so the dominant seventh is written "G7" (or Gdom7), and so are "C9" (dom9), "C11" (dom11), "C13" (dom13),
+// "C6" (maj6) and "C5" (pow, a power chord).
+
+// root, then quality, then alterations, then an optional slash bass:
+// C#  m     7       b5               /G#
+
+pub struct ChordSymbol {
+    // distance in semitones from C of the root
+    root: i64,
+    // intervals in semitones from the root, including the root itself
+    intervals: Vec<i64>,
+    // distance in semitones from C of the bass note
+    bass: Option<i64>,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum VoicingStyle {
+    // all the tones are within an octave from the lowest one
+    Close,
+    // second lowest tone goes an octave up
+    Open,
+    // second highest tone goes an octave down
+    Drop2,
+}
+
+// notesheet settings for the chord symbols
+#[derive(Clone, Copy)]
+pub struct Voicing {
+    // octave of the root of a chord in the root position
+    pub octave: i64,
+    // how many of the lowest tones go an octave up
+    pub inversion: usize,
+    pub style: VoicingStyle,
+}
+
+impl Default for Voicing {
+    fn default() -> Voicing {
+        Voicing {
+            octave: 4,
+            inversion: 0,
+            style: VoicingStyle::Close,
+        }
+    }
+}
+
+impl Voicing {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        match param.0.as_str() {
+            "chord_octave" => {
+                self.octave = match param.1.parse::<i64>() {
+                    Ok(val) => val,
+                    Err(_) => return Err("failed parsing str to i64 in chord_octave"),
+                }
+            }
+            "inversion" => {
+                self.inversion = match param.1.parse::<usize>() {
+                    Ok(val) => val,
+                    Err(_) => return Err("failed parsing str to usize in inversion"),
+                }
+            }
+            "voicing" => {
+                self.style = match param.1.as_str() {
+                    "close" => VoicingStyle::Close,
+                    "open" => VoicingStyle::Open,
+                    "drop2" => VoicingStyle::Drop2,
+                    _ => return Err("unknown voicing, try close, open or drop2"),
+                }
+            }
+            _ => return Err("setting an unexisting parameter"),
+        }
+        Ok(())
+    }
+}
+
+// a symbol starts with an uppercase root: C, Db, F#, and isn't a tone with an octave, such as C4 or B-1,
+// unless it is written in quotes: "G7"
+pub fn is_chord_symbol(s: &str) -> bool {
+    if unquoted(s).is_some() {
+        return true;
+    }
+    let octave = s.get(1..).map(|octave| octave.strip_prefix('-').unwrap_or(octave));
+    let is_tone = matches!(octave, Some(octave) if !octave.is_empty() && octave.chars().all(|c| c.is_ascii_digit()));
+    matches!(s.chars().next(), Some('A'..='G')) && !is_tone
+}
+
+// symbol written in quotes, without them
+fn unquoted(s: &str) -> Option<&str> {
+    s.strip_prefix('"')?.strip_suffix('"')
+}
+
+// distance in semitones from C of a root such as "Eb" or "F#", and the rest of the string
+fn parse_root(s: &str) -> Option<(i64, &str)> {
+    let root = match s.get(0..1)? {
+        "C" => 0,
+        "D" => 2,
+        "E" => 4,
+        "F" => 5,
+        "G" => 7,
+        "A" => 9,
+        "B" => 11,
+        _ => return None,
+    };
+    let rest = &s[1..];
+    Some(match rest.get(0..1) {
+        Some("#") => (root + 1, &rest[1..]),
+        Some("b") => (root - 1, &rest[1..]),
+        _ => (root, rest),
+    })
+}
+
+// qualities, longest first, so that "maj7" is not read as "m" followed by "aj7"
+const QUALITIES: [(&str, &[i64]); 34] = [
+    ("dom13", &[0, 4, 7, 10, 14, 21]),
+    ("maj13", &[0, 4, 7, 11, 14, 21]),
+    ("dom11", &[0, 4, 7, 10, 14, 17]),
+    ("maj11", &[0, 4, 7, 11, 14, 17]),
+    ("mmaj7", &[0, 3, 7, 11]),
+    ("dom9", &[0, 4, 7, 10, 14]),
+    ("maj9", &[0, 4, 7, 11, 14]),
+    ("dom7", &[0, 4, 7, 10]),
+    ("maj7", &[0, 4, 7, 11]),
+    ("maj6", &[0, 4, 7, 9]),
+    ("m7b5", &[0, 3, 6, 10]),
+    ("dim7", &[0, 3, 6, 9]),
+    ("mM7", &[0, 3, 7, 11]),
+    ("m13", &[0, 3, 7, 10, 14, 21]),
+    ("m11", &[0, 3, 7, 10, 14, 17]),
+    ("dim", &[0, 3, 6]),
+    ("aug", &[0, 4, 8]),
+    ("min", &[0, 3, 7]),
+    ("maj", &[0, 4, 7]),
+    ("pow", &[0, 7]),
+    ("m9", &[0, 3, 7, 10, 14]),
+    ("m7", &[0, 3, 7, 10]),
+    ("m6", &[0, 3, 7, 9]),
+    ("M7", &[0, 4, 7, 11]),
+    ("13", &[0, 4, 7, 10, 14, 21]),
+    ("11", &[0, 4, 7, 10, 14, 17]),
+    ("9", &[0, 4, 7, 10, 14]),
+    ("7", &[0, 4, 7, 10]),
+    ("6", &[0, 4, 7, 9]),
+    ("5", &[0, 7]),
+    ("m", &[0, 3, 7]),
+    ("-", &[0, 3, 7]),
+    ("+", &[0, 4, 8]),
+    ("", &[0, 4, 7]),
+];
+
+// alterations change one tone of the chord (or add one): (symbol, tone to replace, new tone)
+const ALTERATIONS: [(&str, Option<i64>, i64); 12] = [
+    ("add13", None, 21),
+    ("add11", None, 17),
+    ("add9", None, 14),
+    ("sus2", Some(4), 2),
+    ("sus4", Some(4), 5),
+    ("sus", Some(4), 5),
+    ("#11", Some(17), 18),
+    ("b13", Some(21), 20),
+    ("b9", Some(14), 13),
+    ("#9", Some(14), 15),
+    ("b5", Some(7), 6),
+    ("#5", Some(7), 8),
+];
+
+impl ChordSymbol {
+    pub fn parse(s: &str) -> Result<ChordSymbol, String> {
+        let s = unquoted(s).unwrap_or(s);
+        let (symbol, bass) = match s.split_once('/') {
+            Some((symbol, bass)) => match parse_root(bass) {
+                Some((bass, "")) => (symbol, Some(bass)),
+                _ => return Err(format!("Failed parsing chord: unknown bass note in \"{s}\"")),
+            },
+            None => (s, None),
+        };
+        let (root, mut rest) = match parse_root(symbol) {
+            Some(root) => root,
+            None => return Err(format!("Failed parsing chord: unknown root in \"{s}\"")),
+        };
+        let mut intervals: Vec<i64> = Vec::new();
+        for (name, quality) in QUALITIES {
+            if let Some(tail) = rest.strip_prefix(name) {
+                intervals = quality.to_vec();
+                rest = tail;
+                break;
+            }
+        }
+        while !rest.is_empty() {
+            let alteration = ALTERATIONS
+                .iter()
+                .find(|(name, _, _)| rest.starts_with(name));
+            match alteration {
+                Some((name, replaced, tone)) => {
+                    // minor thirds are replaced by sus too
+                    let replaced: Vec<i64> = match replaced {
+                        Some(4) => vec![3, 4],
+                        Some(t) => vec![*t],
+                        None => Vec::new(),
+                    };
+                    intervals.retain(|i| !replaced.contains(i));
+                    intervals.push(*tone);
+                    rest = &rest[name.len()..];
+                }
+                None => {
+                    return Err(format!(
+                        "Failed parsing chord: unknown quality \"{rest}\" in \"{s}\" (notes are written in lowercase)"
+                    ))
+                }
+            }
+        }
+        intervals.sort();
+        intervals.dedup();
+        Ok(ChordSymbol {
+            root,
+            intervals,
+            bass,
+        })
+    }
+
+    // distances in semitones from A4 of the tones of this chord, lowest first
+    pub fn voice(&self, voicing: &Voicing) -> Vec<i64> {
+        // C of the chord octave is 9 semitones below A of the same octave
+        let root = (voicing.octave - 4) * 12 - 9 + self.root;
+        let mut tones: Vec<i64> = self.intervals.iter().map(|i| root + i).collect();
+        for _ in 0..voicing.inversion {
+            let lowest = tones.remove(0);
+            tones.push(lowest + 12);
+            tones.sort();
+        }
+        match voicing.style {
+            VoicingStyle::Close => {}
+            VoicingStyle::Open => {
+                if tones.len() >= 3 {
+                    tones[1] += 12;
+                }
+            }
+            VoicingStyle::Drop2 => {
+                if tones.len() >= 3 {
+                    let i = tones.len() - 2;
+                    tones[i] -= 12;
+                }
+            }
+        }
+        tones.sort();
+        if let Some(bass) = self.bass {
+            // bass goes right under the lowest tone of the chord
+            let lowest = tones[0];
+            let below = (lowest - (root - self.root + bass)).rem_euclid(12);
+            tones.insert(0, lowest - if below == 0 { 12 } else { below });
+        }
+        tones
+    }
+}
@@ -0,0 +1,161 @@
+// Effects change a whole track at once, they are chained on the buses and on the master of the mixer, see mixer.rs.
+// Delay and reverb make the track longer, so that their tails are not cut.
+
+// delay 0.3 0.5: echoes every 0.3 seconds, each one half as loud as the previous one
+// reverb 0.7: a room, from 0 (small) to 1 (a hall)
+// lowpass 2000, highpass 300: cutoff frequency in Hz
+// delay and reverb can be given a wet amount from 0 to 1 at the end, "reverb 0.7 0.3",
+// it's 1 by default (only the effect, without the dry sound), which suits buses
+
+use crate::track::{Track, DESIRED_SAMPLE_RATE};
+
+pub trait Effect {
+    fn apply(&self, track: &mut Track);
+}
+
+struct Delay {
+    // seconds
+    time: f64,
+    feedback: f64,
+    wet: f64,
+}
+
+struct Reverb {
+    size: f64,
+    wet: f64,
+}
+
+struct Filter {
+    cutoff: f64,
+    highpass: bool,
+}
+
+// samples in this many seconds
+fn samples(seconds: f64) -> usize {
+    (seconds * DESIRED_SAMPLE_RATE as f64).round().max(1.) as usize
+}
+
+// repeats of a feedback loop until it's too quiet to be heard
+fn repeats(feedback: f64) -> f64 {
+    if feedback <= 0. {
+        1.
+    } else {
+        (0.001_f64.ln() / feedback.ln()).clamp(1., 1000.)
+    }
+}
+
+// dry and wet sound together, the track is already long enough for the wet one
+fn blend(track: &mut Track, wet: Vec<f64>, amount: f64) {
+    for (sample, wet) in track.track.iter_mut().zip(wet) {
+        *sample = *sample * (1. - amount) + wet * amount;
+    }
+}
+
+impl Effect for Delay {
+    fn apply(&self, track: &mut Track) {
+        let delay = samples(self.time);
+        let tail = (delay as f64 * repeats(self.feedback)) as usize;
+        track.track.resize(track.track.len() + tail, 0.);
+        let mut wet = vec![0.; track.track.len()];
+        for i in delay..wet.len() {
+            wet[i] = track.track[i - delay] + self.feedback * wet[i - delay];
+        }
+        blend(track, wet, self.wet);
+    }
+}
+
+// feedback comb filter with a lowpass in the loop, the higher tones die out sooner
+fn comb(input: &[f64], delay: usize, feedback: f64, damping: f64) -> Vec<f64> {
+    let mut output = vec![0.; input.len()];
+    let mut filtered = 0.;
+    for i in delay..input.len() {
+        filtered = output[i - delay] * (1. - damping) + filtered * damping;
+        output[i] = input[i - delay] + feedback * filtered;
+    }
+    output
+}
+
+fn allpass(input: &[f64], delay: usize, feedback: f64) -> Vec<f64> {
+    let mut output = vec![0.; input.len()];
+    for i in 0..input.len() {
+        let delayed = if i >= delay { output[i - delay] } else { 0. };
+        let fed = if i >= delay { input[i - delay] } else { 0. };
+        output[i] = -feedback * input[i] + fed + feedback * delayed;
+    }
+    output
+}
+
+impl Effect for Reverb {
+    // Schroeder reverb: parallel combs make the echoes of the room, allpasses smear them
+    fn apply(&self, track: &mut Track) {
+        // delays of the combs and allpasses in seconds, they don't divide one another so the echoes don't pile up
+        let combs = [0.0353, 0.0367, 0.0338, 0.0322];
+        let allpasses = [0.0051, 0.0126];
+        let feedback = 0.7 + 0.28 * self.size;
+        let tail = (samples(combs[1]) as f64 * repeats(feedback)) as usize;
+        track.track.resize(track.track.len() + tail, 0.);
+        let mut wet = vec![0.; track.track.len()];
+        for delay in combs {
+            for (sum, echo) in wet.iter_mut().zip(comb(&track.track, samples(delay), feedback, 0.2)) {
+                *sum += echo / combs.len() as f64;
+            }
+        }
+        for delay in allpasses {
+            wet = allpass(&wet, samples(delay), 0.5);
+        }
+        blend(track, wet, self.wet);
+    }
+}
+
+impl Effect for Filter {
+    // one pole filter, the highpass is what the lowpass takes away
+    fn apply(&self, track: &mut Track) {
+        let decay = (-2. * std::f64::consts::PI * self.cutoff / DESIRED_SAMPLE_RATE as f64).exp();
+        let mut low = 0.;
+        for sample in &mut track.track {
+            low = *sample * (1. - decay) + low * decay;
+            if self.highpass {
+                *sample -= low;
+            } else {
+                *sample = low;
+            }
+        }
+    }
+}
+
+fn parse_amount(s: Option<&&str>) -> Option<f64> {
+    match s {
+        None => Some(1.),
+        Some(s) => s.parse::<f64>().ok().filter(|amount| (0. ..=1.).contains(amount)),
+    }
+}
+
+// "delay 0.3 0.5", "reverb 0.7 0.3" or "lowpass 2000", None when it's not an effect
+pub fn parse_effect(setting: &str) -> Option<Result<Box<dyn Effect>, &'static str>> {
+    let words: Vec<&str> = setting.split_whitespace().collect();
+    let numbers: Vec<Option<f64>> = words.iter().skip(1).map(|word| word.parse::<f64>().ok()).collect();
+    Some(match (words.first().copied()?, &numbers[..]) {
+        ("delay", [Some(time), Some(feedback), ..]) if *time > 0. && (0. ..1.).contains(feedback) && words.len() <= 4 => {
+            match parse_amount(words.get(3)) {
+                Some(wet) => Ok(Box::new(Delay {
+                    time: *time,
+                    feedback: *feedback,
+                    wet,
+                })),
+                None => Err("the wet amount of delay should be from 0 to 1"),
+            }
+        }
+        ("delay", _) => Err("delay should be a time in seconds and a feedback below 1, such as \"delay 0.3 0.5\""),
+        ("reverb", [Some(size), ..]) if (0. ..=1.).contains(size) && words.len() <= 3 => match parse_amount(words.get(2)) {
+            Some(wet) => Ok(Box::new(Reverb { size: *size, wet })),
+            None => Err("the wet amount of reverb should be from 0 to 1"),
+        },
+        ("reverb", _) => Err("reverb should be a size from 0 to 1, such as \"reverb 0.7\""),
+        (kind @ ("lowpass" | "highpass"), [Some(cutoff)]) if *cutoff > 0. => Ok(Box::new(Filter {
+            cutoff: *cutoff,
+            highpass: kind == "highpass",
+        })),
+        ("lowpass" | "highpass", _) => Err("filters should be a cutoff frequency in Hz, such as \"lowpass 2000\""),
+        _ => return None,
+    })
+}
@@ -0,0 +1,118 @@
+// Groove moves the notes that sit on a grid slightly off it, so that straight notes don't sound mechanical.
+// Every step of the grid has an offset (in fractions of a step) and a velocity multiplier,
+// the pattern of steps repeats from the beginning of every bar.
+// Notes between the steps are moved proportionally, so the order of the notes never changes.
+
+// settings, global or per notesheet:
+// swing: 50 is straight, 66 is a triplet feel, 75 is dotted; moves every second step of the grid
+// groove: straight (default), swing8, swing16, mpc16, shuffle
+// groove_grid: length of a step, 1/8 by default
+// groove_offsets: offsets of the steps of a custom pattern, such as "0 0.1 0 0.15"
+// groove_velocities: velocity multipliers of the steps of a custom pattern, such as "1 0.7 0.9 0.7"
+
+// (name, grid in 1/4th, offsets, velocities)
+const TEMPLATES: [(&str, f64, &[f64], &[f64]); 5] = [
+    ("straight", 0.5, &[0.], &[1.]),
+    // 66% triplet swing of the eighths
+    ("swing8", 0.5, &[0., 1. / 3.], &[1.]),
+    ("swing16", 0.25, &[0., 1. / 3.], &[1.]),
+    // 58% swing of the sixteenths, as on the drum machines
+    ("mpc16", 0.25, &[0., 0.16], &[1., 0.85]),
+    // swung eighths with accented downbeats
+    ("shuffle", 0.5, &[0., 1. / 3.], &[1., 0.75]),
+];
+
+#[derive(Clone)]
+pub struct Groove {
+    // length of a step in 1/4th
+    grid: f64,
+    // offset of every step of the pattern, in fractions of a step
+    offsets: Vec<f64>,
+    // velocity multiplier of every step of the pattern
+    velocities: Vec<f64>,
+}
+
+impl Default for Groove {
+    fn default() -> Groove {
+        Groove {
+            grid: 0.5,
+            offsets: vec![0.],
+            velocities: vec![1.],
+        }
+    }
+}
+
+fn parse_list(s: &str) -> Option<Vec<f64>> {
+    let list: Vec<f64> = s
+        .split_whitespace()
+        .map(|val| val.parse::<f64>())
+        .collect::<Result<Vec<f64>, _>>()
+        .ok()?;
+    if list.is_empty() {
+        return None;
+    }
+    Some(list)
+}
+
+impl Groove {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        match param.0.as_str() {
+            "swing" => {
+                let percentage = match param.1.trim().trim_end_matches('%').parse::<f64>() {
+                    Ok(val) if (0. ..100.).contains(&val) => val,
+                    _ => return Err("swing should be a percentage, 50 is straight and 66 is a triplet feel"),
+                };
+                // second step of every pair goes from the middle of the pair to the percentage of it
+                self.offsets = vec![0., (percentage - 50.) / 50.];
+            }
+            "groove" => {
+                let (_, grid, offsets, velocities) = match TEMPLATES.iter().find(|(name, ..)| *name == param.1.trim()) {
+                    Some(template) => template,
+                    None => return Err("unknown groove, try straight, swing8, swing16, mpc16 or shuffle"),
+                };
+                self.grid = *grid;
+                self.offsets = offsets.to_vec();
+                self.velocities = velocities.to_vec();
+            }
+            "groove_grid" => {
+                self.grid = match param.1.trim().split_once('/').map(|(num, den)| (num.parse::<f64>(), den.parse::<f64>())) {
+                    Some((Ok(num), Ok(den))) if num > 0. && den > 0. => 4. * num / den,
+                    _ => return Err("groove_grid should be a fraction, such as 1/16"),
+                }
+            }
+            "groove_offsets" => {
+                self.offsets = match parse_list(&param.1) {
+                    Some(list) => list,
+                    None => return Err("groove_offsets should be numbers separated by spaces"),
+                }
+            }
+            "groove_velocities" => {
+                self.velocities = match parse_list(&param.1) {
+                    Some(list) => list,
+                    None => return Err("groove_velocities should be numbers separated by spaces"),
+                }
+            }
+            _ => return Err("setting an unexisting parameter"),
+        }
+        Ok(())
+    }
+
+    // position in 1/4th since the beginning of the bar, moved by the groove
+    pub fn shift(&self, position: f64) -> f64 {
+        let steps = position / self.grid;
+        let step = steps.floor();
+        let offset = |step: f64| self.offsets[(step as usize) % self.offsets.len()] * self.grid;
+        let (from, to) = (offset(step), offset(step + 1.));
+        position + from + (to - from) * (steps - step)
+    }
+
+    // velocity multiplier of a note starting at this position since the beginning of the bar,
+    // notes that are not on the grid are left alone
+    pub fn velocity(&self, position: f64) -> f64 {
+        let steps = position / self.grid;
+        if (steps - steps.round()).abs() > 1e-6 {
+            return 1.;
+        }
+        self.velocities[(steps.round() as usize) % self.velocities.len()]
+    }
+}
@@ -2,21 +2,52 @@
 // such as C-sharp (kappa) or D-flats into a collection of frequencies and other data
 // used by intruments in intruments.rs
 
-// string -----------------------| make_note() |---------------------------> note
-// under the hood (even if note_type is an empty struct):
-// string --|from_str(&str)|-> note_type --|to_note(note_type, vec<pars>)|-> note
+// string --|from_str(&str)|-> note_type --|to_note(note_type, position, context)|-> notes
+// (a rest gives no notes at all), parse_bar does it for every note of a bar
 // note type is defined by the instrument
 // e.g sine uses melodic note, and clicks use hit note
 
-pub trait MakeNote {
-    fn make_note(s: &str, pars: Vec<f64>) -> crate::instruments::Note;
+use crate::arpeggio::{ArpMode, Arpeggio};
+use crate::chords::{is_chord_symbol, ChordSymbol, Voicing};
+use crate::groove::Groove;
+use crate::keys::KeySignature;
+use crate::tempo::TempoMap;
+use crate::transform::Transform;
+use crate::tuning::Tuning;
+use crate::vibrato::{parse_bend, Vibrato};
+use std::collections::HashMap;
+
+// everything a note needs to know about the place in the notesheet where it is written
+pub struct NoteContext<'a> {
+    // turns 1/4th since the beginning of the melody into seconds
+    pub tempo: &'a TempoMap,
+    // 1/4th since the beginning of the melody until this bar
+    pub bar_timing: f64,
+    // velocity of the notes that don't have their own
+    pub dynamics: &'a Dynamics,
+    // sequential notes follow one another instead of having their time within the bar written
+    pub sequential: bool,
+    // how chord symbols are turned into tones
+    pub voicing: &'a Voicing,
+    // how tones are turned into frequencies
+    pub tuning: &'a Tuning,
+    // accidentals of the tones that don't have their own
+    pub key: &'a KeySignature,
+    // moves the notes off the grid of the bar
+    pub groove: &'a Groove,
+    // plays chords one tone after another, and sets the steps of the grids
+    pub arp: &'a Arpeggio,
+    // pitch modulation of every note
+    pub vibrato: &'a Vibrato,
+    // transposes and mirrors the tones
+    pub transform: &'a Transform,
 }
 
 pub enum NoteType {
     MelodicNote,
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone, Copy)]
 pub enum ToneName {
     C,
     D,
@@ -30,100 +61,676 @@ pub enum ToneName {
 #[derive(PartialEq, Debug)]
 pub enum ToneVariant {
     Flat,
+    HalfFlat,
+    Natural,
     None,
+    HalfSharp,
     Sharp,
     Numeric(i64),
+    Cents(f64),
+}
+
+fn parse_int(s: &str) -> Result<i64, String> {
+    s.parse::<i64>().map_err(|e| format!("Failed parsing note: {e} in \"{s}\""))
+}
+
+// velocity of a note is stored normalized to [0, 1], v127 and fff being 1.
+// Accepts MIDI-like velocities (v0..v127) and dynamics marks (ppp..fff)
+pub fn parse_velocity(s: &str) -> Option<f64> {
+    let midi: f64 = match s {
+        "ppp" => 16.,
+        "pp" => 33.,
+        "p" => 49.,
+        "mp" => 64.,
+        "mf" => 80.,
+        "f" => 96.,
+        "ff" => 112.,
+        "fff" => 127.,
+        _ => match s.strip_prefix('v').map(|v| v.parse::<f64>()) {
+            Some(Ok(val)) if (0. ..=127.).contains(&val) => val,
+            _ => return None,
+        },
+    };
+    Some(midi / 127.)
+}
+
+// Hairpin is a gradual change of the velocity between two positions, measured in 1/4th since the beginning
+#[derive(Clone)]
+struct Hairpin {
+    start: f64,
+    end: f64,
+    from: f64,
+    to: f64,
 }
 
-fn par_check(length: usize, expected: usize) {
-    if length != expected {
-        panic!("incorrect parsed parameters, expected {expected}, got {length}");
+// Dynamics of a notesheet: the current level and crescendos/diminuendos
+#[derive(Clone)]
+pub struct Dynamics {
+    level: f64,
+    hairpins: Vec<Hairpin>,
+}
+
+impl Default for Dynamics {
+    fn default() -> Dynamics {
+        Dynamics::new(1.)
     }
 }
 
-fn parse_unwrap(s: &str) -> i64 {
-    match s.parse::<i64>() {
-        Ok(val) => val,
-        Err(e) => panic!("Failed parsing note: {e}"),
+impl Dynamics {
+    pub fn new(level: f64) -> Dynamics {
+        Dynamics {
+            level,
+            hairpins: Vec::new(),
+        }
+    }
+    // setting a level cancels previous hairpins
+    pub fn set(&mut self, level: f64) {
+        self.level = level;
+        self.hairpins.clear();
+    }
+    pub fn add_hairpin(&mut self, start: f64, end: f64, from: f64, to: f64) {
+        assert!(end > start, "hairpin should end after it starts");
+        self.hairpins.push(Hairpin {
+            start,
+            end,
+            from,
+            to,
+        });
+    }
+    // inside of a hairpin velocity is interpolated, after it the hairpin's target level is kept
+    pub fn velocity_at(&self, position: f64) -> f64 {
+        let mut velocity = self.level;
+        let mut latest_end = f64::NEG_INFINITY;
+        for hairpin in &self.hairpins {
+            if position >= hairpin.start && position <= hairpin.end {
+                return crate::math::linerp(
+                    (hairpin.start, hairpin.from),
+                    (hairpin.end, hairpin.to),
+                    position,
+                );
+            }
+            if position > hairpin.end && hairpin.end > latest_end {
+                latest_end = hairpin.end;
+                velocity = hairpin.to;
+            }
+        }
+        velocity
     }
 }
 
-pub struct MelodicNote {}
+// Fractions are written as "1/4" or "2". Each trailing dot adds half of the previous value,
+// so "1/4." is 3/8 and "1/4.." is 7/16
+pub fn parse_fraction(s: &str) -> Result<(i64, i64), String> {
+    let dots = s.len() - s.trim_end_matches('.').len();
+    let s = s.trim_end_matches('.');
+    let (num, den) = match s.split_once('/') {
+        Some((num, den)) => (parse_int(num)?, parse_int(den)?),
+        None => (parse_int(s)?, 1),
+    };
+    let dotted = 1_i64 << dots;
+    Ok((num * (2 * dotted - 1), den * dotted))
+}
 
-impl MakeNote for MelodicNote {
-    fn make_note(s: &str, pars: Vec<f64>) -> crate::instruments::Note {
-        // parameter 1: BPM in 1/4th per minute
-        // parameter 2: 1/4th since the beginning of the melody until this bar
-        par_check(pars.len(), 2);
+// what sounds when a note is played
+#[derive(Clone, Copy)]
+pub enum Tone {
+    // distance in semitones from A4, fractions of a semitone are added on top of the tuned tone
+    Semitones(f64),
+    // exact frequency, tuning is not applied
+    Hz(f64),
+}
 
-        let mut split: Vec<&str> = s.split(' ').collect();
+impl Tone {
+    // distance in semitones from A4, tones written as frequencies are measured in equal tempered semitones
+    pub fn semitones(self) -> f64 {
+        match self {
+            Tone::Semitones(semitones) => semitones,
+            Tone::Hz(freq) => 12. * (freq / 440.).log2(),
+        }
+    }
 
-        let mut pop_to_fraq = || -> (i64, i64) {
-            let s = split.pop().unwrap();
-            if s.contains('/') {
-                let temp_s: Vec<&str> = s.split('/').collect();
-                (parse_unwrap(temp_s[0]), parse_unwrap(temp_s[1]))
+    pub fn frequency(self, tuning: &Tuning) -> f64 {
+        match self {
+            Tone::Semitones(semitones) => tuning.frequency(semitones),
+            Tone::Hz(freq) => freq,
+        }
+    }
+}
+
+enum Pitch {
+    // rests take time, but produce no sound
+    Rest,
+    // a single tone or a bracket chord
+    Tones(Vec<Tone>),
+    // chord symbols are voiced with the notesheet settings
+    Chord(ChordSymbol),
+}
+
+// A note as it is written in the notesheet, before it is placed in time
+pub struct MelodicNote {
+    pitch: Pitch,
+    // time after the beginning of the bar, sequential notes don't have it
+    delta: Option<(i64, i64)>,
+    length: (i64, i64),
+    velocity: Option<f64>,
+    tie: bool,
+    // vibrato depth of this note, instead of the one of the notesheet
+    vibrato: Option<f64>,
+    // (semitones, time after the start of the note) points, see vibrato.rs
+    bends: Vec<(f64, Option<(i64, i64)>)>,
+}
+
+impl MelodicNote {
+    // explicit notes look like "g4 flat 0 1/4", sequential ones don't have the time: "g4 flat 1/4"
+    // instead of a single tone there can be a rest "r", a bracket chord "[c4 e4 g4]" or a chord symbol "Cmaj7"
+    pub fn from_str(s: &str, sequential: bool, accidentals: &mut Accidentals) -> Result<MelodicNote, String> {
+        let s = s.trim();
+        // bracket chords contain spaces, so they are cut out before splitting
+        let (bracket, tail) = match s.strip_prefix('[') {
+            Some(inner) => match inner.split_once(']') {
+                Some((inner, tail)) => (Some(inner), tail),
+                None => return Err(format!("Failed parsing chord: no closing bracket in \"{s}\"")),
+            },
+            None => (None, s),
+        };
+        let mut split: Vec<&str> = tail.split_whitespace().collect();
+        // chord symbols are case sensitive, everything else is not
+        let first = match bracket {
+            Some(_) => None,
+            None if split.is_empty() => return Err("Failed parsing note: empty note".to_string()),
+            None => Some(split.remove(0)),
+        };
+        let lowered: Vec<String> = split.iter().map(|t| t.to_ascii_lowercase()).collect();
+        let mut split: Vec<&str> = lowered.iter().map(|t| t.as_str()).collect();
+        let positional = if sequential { 1 } else { 2 };
+
+        // optional velocity, tie marker "~", vibrato "vib:0.5" and bends "bend:+2@1/4"
+        // go after the length of the note, in any order
+        let mut velocity: Option<f64> = None;
+        let mut tie = false;
+        let mut vibrato: Option<f64> = None;
+        let mut bends: Vec<(f64, Option<(i64, i64)>)> = Vec::new();
+        while split.len() > positional {
+            let last = split[split.len() - 1];
+            if last == "~" {
+                tie = true;
+            } else if let Some(depth) = last.strip_prefix("vib:") {
+                vibrato = match depth.parse::<f64>() {
+                    Ok(depth) if depth >= 0. => Some(depth),
+                    _ => return Err(format!("Failed parsing note: vibrato depth should be a number of semitones in \"{s}\"")),
+                };
+            } else if let Some(bend) = last.strip_prefix("bend:") {
+                match parse_bend(bend) {
+                    Some(bend) => bends.push(bend),
+                    None => return Err(format!("Failed parsing note: bends look like \"bend:+2@1/4\" in \"{s}\"")),
+                }
+            } else if let Some(val) = parse_velocity(last) {
+                velocity = Some(val);
             } else {
-                (parse_unwrap(s), 1)
+                break;
             }
-        };
+            split.pop();
+        }
 
+        if split.len() < positional {
+            return Err(format!("Failed parsing note: expected a tone, a time and a length (time is omitted in sequential notesheets) in \"{s}\""));
+        }
         // Length of the note
-        let length = pop_to_fraq();
+        let length = parse_fraction(split.pop().unwrap())?;
         // Time after the beginning of the bar
-        let delta = pop_to_fraq();
-        // Standard names for the tones, but can also be an integer (in that case C = 0, D = 1, etc up to B = 6)
-        let tone: ToneName = match &split[0][0..1] {
-            "b" => ToneName::B,
-            "c" => ToneName::C,
-            "d" => ToneName::D,
-            "e" => ToneName::E,
-            "f" => ToneName::F,
-            "g" => ToneName::G,
-            "a" => ToneName::A,
-            _ => panic!("Failed while parsing note: unknown tone tame"),
+        let delta = if sequential {
+            None
+        } else {
+            Some(parse_fraction(split.pop().unwrap())?)
+        };
+
+        // what's left is the variant of a single tone
+        let pitch = match (bracket, first) {
+            (Some(inner), _) if split.is_empty() => Pitch::Tones(parse_bracket_chord(inner, accidentals)?),
+            (None, Some(symbol)) if is_chord_symbol(symbol) => {
+                if !split.is_empty() {
+                    return Err(format!("Failed parsing note: chords can't have variants in \"{s}\""));
+                }
+                Pitch::Chord(ChordSymbol::parse(symbol)?)
+            }
+            (None, Some(tone)) => {
+                let tone = tone.to_ascii_lowercase();
+                if tone == "r" || tone == "rest" {
+                    Pitch::Rest
+                } else {
+                    Pitch::Tones(vec![parse_tone(&[[tone.as_str()].as_slice(), &split].concat(), accidentals)?])
+                }
+            }
+            _ => return Err(format!("Failed parsing note: chords can't have variants in \"{s}\"")),
+        };
+        Ok(MelodicNote {
+            pitch,
+            delta,
+            length,
+            velocity,
+            tie,
+            vibrato,
+            bends,
+        })
+    }
+
+    // length in 1/4th
+    pub fn length(&self) -> f64 {
+        4. * self.length.0 as f64 / self.length.1 as f64
+    }
+
+    // position: 1/4th since the beginning of the melody, scale: stretches the length (used by tuplets)
+    pub fn to_note(&self, position: f64, scale: f64, ctx: &NoteContext) -> Vec<crate::instruments::Note> {
+        let tones = match &self.pitch {
+            Pitch::Rest => Vec::new(),
+            Pitch::Tones(tones) => tones.clone(),
+            Pitch::Chord(chord) => chord
+                .voice(ctx.voicing)
+                .into_iter()
+                .map(|semitones| Tone::Semitones(semitones as f64))
+                .collect(),
         };
-        // octave number. A440 is in the 4th one. Can be negative.
-        let octave: i64 = parse_unwrap(split[0].get(1..).unwrap());
-        // flats and sharps. Can be an integrer, negatives - flats, positives - sharps
-        let mut variant = ToneVariant::None;
-        // if split length is not 2, then variant is implied
-        if split.len() == 2 {
-            variant = match split.pop().unwrap() {
+        let length = self.length() * scale;
+        if ctx.arp.is_on() && !tones.is_empty() {
+            // steps are cut to the length of the note
+            let step = ctx.arp.step();
+            let count = (length / step - 1e-9).ceil().max(1.) as usize;
+            return ctx
+                .arp
+                .steps(&tones, count, position.to_bits())
+                .into_iter()
+                .enumerate()
+                .map(|(i, tone)| {
+                    let offset = i as f64 * step;
+                    self.sound(tone, position + offset, step.min(length - offset), ctx)
+                        .with_tie(self.tie && i + 1 == count)
+                })
+                .collect();
+        }
+        tones
+            .into_iter()
+            .map(|tone| self.sound(tone, position, length, ctx).with_tie(self.tie))
+            .collect()
+    }
+
+    // one tone of this note, position and length in 1/4th
+    fn sound(&self, tone: Tone, position: f64, length: f64, ctx: &NoteContext) -> crate::instruments::Note {
+        let in_bar = position - ctx.bar_timing;
+        let velocity = self
+            .velocity
+            .unwrap_or_else(|| ctx.dynamics.velocity_at(position))
+            * ctx.groove.velocity(in_bar);
+        // both ends are moved, so that the swung notes keep touching each other
+        let start = ctx.bar_timing + ctx.groove.shift(in_bar);
+        let end = ctx.bar_timing + ctx.groove.shift(in_bar + length);
+        let time = ctx.tempo.seconds(start);
+        let freq = ctx.transform.tone(tone).frequency(ctx.tuning);
+        let bends = self
+            .bends
+            .iter()
+            .map(|(semitones, at)| {
+                let at = match at {
+                    Some((num, den)) => start + 4. * *num as f64 / *den as f64,
+                    None => end,
+                };
+                (ctx.tempo.seconds(at) - time, freq * (semitones / 12.).exp2())
+            })
+            .collect();
+        let mut vibrato = *ctx.vibrato;
+        if let Some(depth) = self.vibrato {
+            vibrato.depth = depth;
+        }
+        crate::instruments::Note::new(
+            freq,                          // freq
+            ctx.tempo.seconds(end) - time, // leng
+            time,                          // time
+        )
+        .with_loud(velocity)
+        .with_bends(bends)
+        .with_vibrato(vibrato)
+    }
+}
+
+// tones of a bracket chord, such as "c4 e4 flat g4", each can have its own variant
+fn parse_bracket_chord(s: &str, accidentals: &mut Accidentals) -> Result<Vec<Tone>, String> {
+    let lowered = s.to_ascii_lowercase();
+    let mut tones: Vec<Vec<&str>> = Vec::new();
+    for token in lowered.split_whitespace() {
+        // a tone is a letter followed by an octave or a frequency, anything else is a variant of the previous tone
+        let is_tone = (token.starts_with(|c: char| c.is_ascii_alphabetic())
+            && token[1..].parse::<i64>().is_ok())
+            || parse_hz(token).is_some();
+        match tones.last_mut() {
+            Some(tone) if !is_tone => tone.push(token),
+            _ => tones.push(vec![token]),
+        }
+    }
+    if tones.is_empty() {
+        return Err("Failed parsing chord: empty brackets".to_string());
+    }
+    tones.iter().map(|tone| parse_tone(tone, accidentals)).collect()
+}
+
+// frequency of a tone written as "440hz"
+fn parse_hz(s: &str) -> Option<f64> {
+    match s.strip_suffix("hz").map(|freq| freq.parse::<f64>()) {
+        Some(Ok(freq)) if freq > 0. => Some(freq),
+        _ => None,
+    }
+}
+
+// microtonal shift of a tone written as "+14c" or "-30c"
+fn parse_cents(s: &str) -> Option<f64> {
+    match s.strip_suffix('c').map(|cents| cents.parse::<f64>()) {
+        Some(Ok(cents)) => Some(cents),
+        _ => None,
+    }
+}
+
+// a tone, such as ["g4"], ["g4", "flat"], ["g4", "-2"], ["a4", "half-sharp", "+14c"] or ["440hz"]
+// tone written outside of the bars, such as "c4", "f4 sharp" or "440hz"
+pub fn parse_pitch(s: &str) -> Result<Tone, String> {
+    let split: Vec<&str> = s.split_whitespace().collect();
+    if split.is_empty() {
+        return Err(format!("Failed while parsing note: no tone in \"{s}\""));
+    }
+    parse_tone(&split, &mut Accidentals::new(&KeySignature::default()))
+}
+
+fn parse_tone(split: &[&str], accidentals: &mut Accidentals) -> Result<Tone, String> {
+    if let Some(freq) = parse_hz(split[0]) {
+        if split.len() > 1 {
+            return Err("Failed while parsing note: frequency notes can't have variants".to_string());
+        }
+        return Ok(Tone::Hz(freq));
+    }
+    // Standard names for the tones
+    let tone: ToneName = match split[0].get(0..1) {
+        Some("b") => ToneName::B,
+        Some("c") => ToneName::C,
+        Some("d") => ToneName::D,
+        Some("e") => ToneName::E,
+        Some("f") => ToneName::F,
+        Some("g") => ToneName::G,
+        Some("a") => ToneName::A,
+        _ => return Err(format!("Failed while parsing note: unknown tone name in \"{}\"", split[0])),
+    };
+    // octave number. A440 is in the 4th one. Can be negative, goes from -1 to 9 as in MIDI.
+    let octave: i64 = parse_int(split[0].get(1..).unwrap())?;
+    if !(-1..=9).contains(&octave) {
+        return Err(format!(
+            "Failed while parsing note: octave of \"{}\" is out of range, tones go from octave -1 to 9 \
+             (chord symbols with a number only are written in quotes, such as \"G13\")",
+            split[0]
+        ));
+    }
+    // flats and sharps. Can be an integrer, negatives - flats, positives - sharps
+    // quarter-tones and cents, such as +14c, make the tone microtonal. Variants add up
+    // if split length is 1, then variant is implied
+    let variants: Vec<ToneVariant> = split[1..]
+        .iter()
+        .map(|val| {
+            Ok(match *val {
                 "flat" => ToneVariant::Flat,
+                "half-flat" => ToneVariant::HalfFlat,
+                "natural" => ToneVariant::Natural,
+                "half-sharp" => ToneVariant::HalfSharp,
                 "sharp" => ToneVariant::Sharp,
                 val => {
                     if val.parse::<f64>().is_ok() {
-                        ToneVariant::Numeric(parse_unwrap(val))
+                        ToneVariant::Numeric(parse_int(val)?)
+                    } else if let Some(cents) = parse_cents(val) {
+                        ToneVariant::Cents(cents)
                     } else {
                         ToneVariant::None
                     }
                 }
-            };
-        }
-
-        // semitones is a distance, in semitones, from A4
-        let mut semitones: i64;
-        semitones = (octave - 4) * 12;
-        semitones += match tone {
-            ToneName::C => -9,
-            ToneName::D => -7,
-            ToneName::E => -5,
-            ToneName::F => -4,
-            ToneName::G => -2,
-            ToneName::A => 0,
-            ToneName::B => 2,
-        };
-        semitones += match variant {
-            ToneVariant::Flat => -1,
-            ToneVariant::None => 0,
-            ToneVariant::Sharp => 1,
-            ToneVariant::Numeric(val) => val,
-        };
-        crate::instruments::Note::new(
-            440. * (semitones as f64 / 12.).exp2(),             // freq
-            length.0 as f64 / length.1 as f64 * 240. / pars[0], // leng
-            (pars[1] + 4. * delta.0 as f64 / delta.1 as f64) * 60. / pars[0], // time
-        )
+            })
+        })
+        .collect::<Result<Vec<ToneVariant>, String>>()?;
+
+    // semitones is a distance, in semitones, from A4
+    let mut semitones: f64;
+    semitones = ((octave - 4) * 12) as f64;
+    semitones += match tone {
+        ToneName::C => -9.,
+        ToneName::D => -7.,
+        ToneName::E => -5.,
+        ToneName::F => -4.,
+        ToneName::G => -2.,
+        ToneName::A => 0.,
+        ToneName::B => 2.,
+    };
+    // accidentals, numeric variants included, replace the ones from the key signature and earlier in the bar,
+    // cents are added on top of them
+    let mut accidental: Option<f64> = None;
+    for variant in variants {
+        let mut written = |shift: f64| accidental = Some(accidental.unwrap_or(0.) + shift);
+        match variant {
+            ToneVariant::Flat => written(-1.),
+            ToneVariant::HalfFlat => written(-0.5),
+            ToneVariant::Natural => written(0.),
+            ToneVariant::None => {}
+            ToneVariant::HalfSharp => written(0.5),
+            ToneVariant::Sharp => written(1.),
+            ToneVariant::Numeric(val) => written(val as f64),
+            ToneVariant::Cents(cents) => semitones += cents / 100.,
+        }
+    }
+    semitones += match accidental {
+        Some(shift) => {
+            accidentals.write(tone, octave, shift);
+            shift
+        }
+        None => accidentals.get(tone, octave),
+    };
+    Ok(Tone::Semitones(semitones))
+}
+
+// Accidentals in effect within a bar: the key signature, and the ones written earlier in the bar,
+// which last for the same tone name and octave until the end of the bar
+pub struct Accidentals {
+    key: KeySignature,
+    written: HashMap<(usize, i64), f64>,
+}
+
+impl Accidentals {
+    pub fn new(key: &KeySignature) -> Accidentals {
+        Accidentals {
+            key: *key,
+            written: HashMap::new(),
+        }
+    }
+    fn get(&self, tone: ToneName, octave: i64) -> f64 {
+        match self.written.get(&(tone as usize, octave)) {
+            Some(shift) => *shift,
+            None => self.key.accidental(tone as usize),
+        }
+    }
+    fn write(&mut self, tone: ToneName, octave: i64, shift: f64) {
+        self.written.insert((tone as usize, octave), shift);
+    }
+}
+
+// splits s by the separator, skipping the ones inside of brackets
+pub fn split_top_level(s: &str, separator: char) -> Vec<&str> {
+    let mut parts: Vec<&str> = Vec::new();
+    let mut depth: i64 = 0;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match c {
+            '{' | '[' | '(' => depth += 1,
+            '}' | ']' | ')' => depth -= 1,
+            c if c == separator && depth == 0 => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+// Turns everything after the bar number into notes.
+// Elements are either notes or tuplets: "3:2{e4 1/8, f4 1/8, g4 1/8}" plays 3 notes in the time of 2.
+// Notes inside of a tuplet always follow one another, in explicit notesheets the tuplet itself
+// is given the time after the beginning of the bar: "3:2{e4 1/8, f4 1/8, g4 1/8} 1/4"
+pub fn parse_bar(s: &str, ctx: &NoteContext) -> Result<Vec<crate::instruments::Note>, String> {
+    let mut cursor = ctx.bar_timing;
+    let mut accidentals = Accidentals::new(ctx.key);
+    parse_elements(s, ctx, ctx.sequential, &mut cursor, 1., &mut accidentals)
+}
+
+fn parse_elements(
+    s: &str,
+    ctx: &NoteContext,
+    sequential: bool,
+    cursor: &mut f64,
+    scale: f64,
+    accidentals: &mut Accidentals,
+) -> Result<Vec<crate::instruments::Note>, String> {
+    let mut notes: Vec<crate::instruments::Note> = Vec::new();
+    for element in split_top_level(s, ',') {
+        let element = element.trim();
+        if element.is_empty() {
+            continue;
+        }
+        match (element.find('{'), element.rfind('}')) {
+            (Some(open), Some(close)) => {
+                let (count, span) = match element[..open].trim().split_once(':') {
+                    Some((count, span)) => (parse_int(count.trim())?, parse_int(span.trim())?),
+                    None => return Err(format!("Failed parsing tuplet: expected \"n:m{{...}}\" in \"{element}\"")),
+                };
+                let tail = element[close + 1..].trim();
+                let mut inner_cursor = if sequential {
+                    if !tail.is_empty() {
+                        return Err(format!("Failed parsing tuplet: sequential tuplets don't have time in \"{element}\""));
+                    }
+                    *cursor
+                } else {
+                    let delta = parse_fraction(tail)?;
+                    ctx.bar_timing + 4. * delta.0 as f64 / delta.1 as f64
+                };
+                notes.extend(parse_elements(
+                    &element[open + 1..close],
+                    ctx,
+                    true,
+                    &mut inner_cursor,
+                    scale * span as f64 / count as f64,
+                    accidentals,
+                )?);
+                if sequential {
+                    *cursor = inner_cursor;
+                }
+            }
+            (None, None) if grid_steps(element).is_some() => {
+                // grids are lanes, the ones of a bar play together and don't move the cursor
+                let (pitch, steps) = grid_steps(element).unwrap();
+                let start = if sequential { *cursor } else { ctx.bar_timing };
+                notes.extend(parse_grid(pitch, steps, start, scale, ctx, accidentals)?);
+            }
+            (None, None) => {
+                let note = MelodicNote::from_str(element, sequential, accidentals)?;
+                let position = match note.delta {
+                    Some(delta) => ctx.bar_timing + 4. * delta.0 as f64 / delta.1 as f64,
+                    None => *cursor,
+                };
+                notes.extend(note.to_note(position, scale, ctx));
+                *cursor = position + note.length() * scale;
+            }
+            _ => return Err(format!("Failed parsing notes: unbalanced braces in \"{element}\"")),
+        }
+    }
+    Ok(notes)
+}
+
+// "c3 x..x-.X." as the tone and the steps
+fn grid_steps(element: &str) -> Option<(&str, &str)> {
+    let (pitch, steps) = element.rsplit_once(char::is_whitespace)?;
+    let is_grid = steps.chars().all(|c| matches!(c, 'x' | 'X' | '.' | '-')) && steps.contains(['x', 'X']);
+    if is_grid {
+        Some((pitch.trim(), steps))
+    } else {
+        None
+    }
+}
+
+// notes of a step sequencer grid, starting at the position,
+// the pitch can have a velocity, such as "c3 v80", which the accented hits replace
+fn parse_grid(
+    pitch: &str,
+    steps: &str,
+    start: f64,
+    scale: f64,
+    ctx: &NoteContext,
+    accidentals: &mut Accidentals,
+) -> Result<Vec<crate::instruments::Note>, String> {
+    let (num, den) = ctx.arp.grid;
+    // velocity goes after the length of the note, the tone and its variants before it
+    let mut tone: Vec<&str> = pitch.split_whitespace().collect();
+    let velocity = match tone.last() {
+        Some(last) if tone.len() > 1 && parse_velocity(&last.to_ascii_lowercase()).is_some() => tone.pop().unwrap(),
+        _ => "",
+    };
+    let mut hit = MelodicNote::from_str(&format!("{} {num}/{den} {velocity}", tone.join(" ")), true, accidentals)?;
+    let velocity = hit.velocity;
+    let step = ctx.arp.grid_step() * scale;
+    // the grid is the rhythm itself, so its hits are not arpeggiated
+    let off = Arpeggio {
+        mode: ArpMode::Off,
+        ..ctx.arp.clone()
+    };
+    let steady = NoteContext { arp: &off, ..*ctx };
+    let mut notes: Vec<crate::instruments::Note> = Vec::new();
+    let steps: Vec<char> = steps.chars().collect();
+    for (i, c) in steps.iter().enumerate() {
+        if !matches!(c, 'x' | 'X') {
+            continue;
+        }
+        let held = steps[i + 1..].iter().take_while(|c| **c == '-').count();
+        // accented hits are played as loud as possible
+        hit.velocity = if *c == 'X' { Some(1.) } else { velocity };
+        notes.extend(hit.to_note(start + i as f64 * step, scale * (held + 1) as f64, &steady));
+    }
+    Ok(notes)
+}
+
+// Tied notes are merged with the next note of the same pitch that starts right when they end,
+// so that the sound continues without a new attack. Notes should be in the order they were written.
+// A tied note without such a continuation is an error.
+pub fn merge_ties(notes: Vec<crate::instruments::Note>) -> Result<Vec<crate::instruments::Note>, String> {
+    let mut merged: Vec<crate::instruments::Note> = Vec::with_capacity(notes.len());
+    // indices of merged notes that are waiting for their continuation
+    let mut open: Vec<usize> = Vec::new();
+    for note in notes {
+        let continued = open.iter().position(|&i| {
+            let tied = &merged[i];
+            (tied.freq() - note.freq()).abs() <= 1e-9 * note.freq()
+                && (tied.time() + tied.leng() - note.time()).abs() < 1e-6
+        });
+        match continued {
+            Some(pos) => {
+                let i = open.remove(pos);
+                merged[i].absorb(&note);
+                if note.tied() {
+                    open.push(i);
+                }
+            }
+            None => {
+                if note.tied() {
+                    open.push(merged.len());
+                }
+                merged.push(note);
+            }
+        }
+    }
+    if let Some(&i) = open.first() {
+        return Err(format!(
+            "tied note of {:.2} Hz at {:.3} seconds is not followed by a note of the same pitch",
+            merged[i].freq(),
+            merged[i].time()
+        ));
     }
+    Ok(merged)
 }
@@ -0,0 +1,73 @@
+// Humanize moves every note by a small random amount, so that the melody sounds played rather than programmed.
+// Random numbers come from a seeded generator, so the same file always gives the same wav.
+
+// settings, global or per notesheet:
+// humanize: overall amount, 1 is a gentle player (10 ms, 5% of the length, 0.05 velocity, 5 cents)
+// humanize_timing: largest shift of the start of a note, in seconds
+// humanize_length: largest change of the length of a note, in fractions of the length
+// humanize_velocity: largest change of the velocity of a note
+// humanize_detune: largest detune of a note, in cents
+// humanize_seed: any whole number, 0 by default
+
+use crate::instruments::Note;
+use crate::math::Rng;
+
+#[derive(Clone, Default)]
+pub struct Humanize {
+    timing: f64,
+    length: f64,
+    velocity: f64,
+    detune: f64,
+    seed: u64,
+}
+
+impl Humanize {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        if param.0 == "humanize_seed" {
+            self.seed = match param.1.trim().parse::<u64>() {
+                Ok(val) => val,
+                Err(_) => return Err("humanize_seed should be a whole positive number"),
+            };
+            return Ok(());
+        }
+        let amount = match param.1.trim().parse::<f64>() {
+            Ok(val) if val >= 0. => val,
+            _ => return Err("humanize amounts should be positive numbers"),
+        };
+        match param.0.as_str() {
+            "humanize" => {
+                self.timing = 0.01 * amount;
+                self.length = 0.05 * amount;
+                self.velocity = 0.05 * amount;
+                self.detune = 5. * amount;
+            }
+            "humanize_timing" => self.timing = amount,
+            "humanize_length" => self.length = amount,
+            "humanize_velocity" => self.velocity = amount,
+            "humanize_detune" => self.detune = amount,
+            _ => return Err("setting an unexisting parameter"),
+        }
+        Ok(())
+    }
+
+    // stream tells apart the notesheets, so that the same notes in two of them are not moved the same way
+    pub fn apply(&self, notes: Vec<Note>, stream: u64) -> Vec<Note> {
+        if self.timing == 0. && self.length == 0. && self.velocity == 0. && self.detune == 0. {
+            return notes;
+        }
+        let mut rng = Rng::new(self.seed ^ stream.wrapping_mul(0x2545F4914F6CDD1D));
+        notes
+            .into_iter()
+            .map(|mut note| {
+                // every note takes the same amount of numbers, whatever the settings are
+                let (time, length, velocity, detune) =
+                    (rng.symmetric(), rng.symmetric(), rng.symmetric(), rng.symmetric());
+                note.map_freq(|freq| freq * (detune * self.detune / 1200.).exp2());
+                note.stretch((1. + length * self.length).max(0.));
+                note.set_time((note.time() + time * self.timing).max(0.));
+                note.set_loud((note.loud() + velocity * self.velocity).clamp(0., 1.));
+                note
+            })
+            .collect()
+    }
+}
@@ -1,12 +1,10 @@
+use crate::harmonics::NoteType;
 use crate::math;
 use crate::track;
-use crate::track::{Mask, Track};
+use crate::track::{Gain, Mask, Track};
+use crate::vibrato::Vibrato;
+use std::collections::HashMap;
 
-pub enum InstrumentList {
-    None,
-    SineWave,
-    Xylophone,
-}
 // Note is a struct that contains data about >>>main<<< frequency of a sound,
 // it's length and when it starts. Different instruments will produce different soundwaves.
 pub struct Note {
@@ -16,8 +14,15 @@ pub struct Note {
     leng: f64,
     // Time from the start of the track, when this should be played, in seconds.
     time: f64,
-    // Relative loudness of the note
+    // Relative loudness (velocity) of the note, within [0, 1]
     loud: f64,
+    // Tied notes continue into the next note of the same pitch, see harmonics::merge_ties
+    tied: bool,
+    // (seconds since the start of the note, frequency) points the frequency goes through, evenly on a
+    // logarithmic scale in between. Empty when the frequency doesn't change, see legato.rs and vibrato.rs
+    glide: Vec<(f64, f64)>,
+    // wobbles the frequency around the glide
+    vibrato: Vibrato,
 }
 
 impl Note {
@@ -27,6 +32,9 @@ impl Note {
             leng: 0.,
             time: 0.,
             loud: 0.,
+            tied: false,
+            glide: Vec::new(),
+            vibrato: Vibrato::default(),
         }
     }
     pub fn new(freq: f64, leng: f64, time: f64) -> Note {
@@ -35,7 +43,136 @@ impl Note {
             leng,
             time,
             loud: 1.,
+            tied: false,
+            glide: Vec::new(),
+            vibrato: Vibrato::default(),
+        }
+    }
+
+    pub fn with_loud(mut self, loud: f64) -> Note {
+        self.loud = loud;
+        self
+    }
+
+    pub fn with_tie(mut self, tied: bool) -> Note {
+        self.tied = tied;
+        self
+    }
+
+    pub fn with_vibrato(mut self, vibrato: Vibrato) -> Note {
+        self.vibrato = vibrato;
+        self
+    }
+    // bends to the given (seconds since the start of the note, frequency) points
+    pub fn with_bends(mut self, bends: Vec<(f64, f64)>) -> Note {
+        self.glide = bends;
+        self.glide.sort_by(|a, b| a.0.total_cmp(&b.0));
+        self
+    }
+    // continues this note for the length of the next one
+    pub fn absorb(&mut self, next: &Note) {
+        self.leng = next.time + next.leng - self.time;
+        self.tied = next.tied;
+    }
+    // continues this note with the pitch of the next one, sliding to it for the portamento time (in seconds)
+    pub fn glide_to(&mut self, next: &Note, portamento: f64) {
+        let offset = next.time - self.time;
+        let from = self.freq_at(offset);
+        // next note takes over, so the points after its start don't matter anymore
+        self.glide.retain(|(time, _)| *time < offset);
+        self.glide.push((offset, from));
+        let slide = portamento.min(next.leng);
+        self.glide.push((offset + slide, next.freq));
+        // bends of the next note go on after the slide
+        self.glide
+            .extend(next.glide.iter().filter(|(time, _)| *time > slide).map(|(time, freq)| (offset + time, *freq)));
+        self.leng = offset + next.leng;
+        self.tied = next.tied;
+    }
+
+    // transforms and humanize change notes in place, so that their glide changes with them
+    pub fn map_freq(&mut self, change: impl Fn(f64) -> f64) {
+        self.freq = change(self.freq);
+        for point in &mut self.glide {
+            point.1 = change(point.1);
+        }
+    }
+    pub fn stretch(&mut self, factor: f64) {
+        self.leng *= factor;
+        for point in &mut self.glide {
+            point.0 *= factor;
+        }
+    }
+    // the pitch goes the other way round, for notes played backwards
+    pub fn reverse_glide(&mut self) {
+        let Some(&(_, last)) = self.glide.last() else {
+            return;
+        };
+        let mut glide: Vec<(f64, f64)> = self.glide.iter().rev().map(|&(time, freq)| ((self.leng - time).max(0.), freq)).collect();
+        glide.push((self.leng, self.freq));
+        self.freq = last;
+        self.glide = glide;
+    }
+    pub fn set_time(&mut self, time: f64) {
+        self.time = time;
+    }
+    pub fn set_loud(&mut self, loud: f64) {
+        self.loud = loud;
+    }
+
+    // frequency at this many seconds since the start of the note
+    pub fn freq_at(&self, t: f64) -> f64 {
+        self.glide_at(t.max(0.)) * self.vibrato.factor(t)
+    }
+    fn glide_at(&self, t: f64) -> f64 {
+        let mut from = (0., self.freq);
+        for &(time, freq) in &self.glide {
+            if t < time {
+                if time <= from.0 {
+                    return from.1;
+                }
+                return from.1 * (freq / from.1).powf((t - from.0) / (time - from.0));
+            }
+            from = (time, freq);
         }
+        from.1
+    }
+    // notes whose pitch doesn't change can be played with a fixed frequency
+    pub fn is_modulated(&self) -> bool {
+        !self.glide.is_empty() || self.vibrato.is_on()
+    }
+    // phase of an oscillator that follows the frequency of the note, at every sample
+    // starting from this many seconds since the start of the note
+    pub fn phases(&self, freq_mod: f64, from: f64, samples: usize) -> Vec<f64> {
+        let dt = 1. / track::DESIRED_SAMPLE_RATE as f64;
+        let mut phase = 0.;
+        (0..samples)
+            .map(|i| {
+                let current = phase;
+                phase += 2. * std::f64::consts::PI * freq_mod * self.freq_at(from + i as f64 * dt) * dt;
+                current
+            })
+            .collect()
+    }
+
+    pub fn freq(&self) -> f64 {
+        self.freq
+    }
+    pub fn leng(&self) -> f64 {
+        self.leng
+    }
+    pub fn time(&self) -> f64 {
+        self.time
+    }
+    pub fn loud(&self) -> f64 {
+        self.loud
+    }
+    pub fn tied(&self) -> bool {
+        self.tied
+    }
+    // velocity to amplitude curve, so that dynamics marks are evenly spaced to the ear
+    pub fn amplitude(&self) -> f64 {
+        self.loud * self.loud
     }
 
     pub fn next(&self, freq: f64, leng: f64) -> Note {
@@ -67,8 +204,10 @@ impl fmt::Display for Note {
 
 // Instruments are compilation of methods and coefficients that turn notes into soundwaves
 // Simplest one is a sinewave.
+// The trait is object safe, so instruments can be stored in the Registry as Box<dyn Instrument>
 
 pub trait Instrument {
+    #[allow(clippy::wrong_self_convention)]
     fn from_parameters(&mut self, parameters: Vec<&(String, String)>) -> Result<i64, &'static str> {
         let mut successes: i64 = 0;
         for param in parameters {
@@ -77,7 +216,7 @@ pub trait Instrument {
                 Err(e) => return Err(e),
             }
         }
-        return Ok(successes);
+        Ok(successes)
     }
     fn track_from_notes(&self, part: &Vec<Note>) -> Track {
         let mut temp_track: Track = Track::new();
@@ -88,11 +227,66 @@ pub trait Instrument {
         }
         temp_track
     }
-    fn new() -> Self;
+    // kind of notes this instrument expects from the notesheet
+    fn note_type(&self) -> NoteType {
+        NoteType::MelodicNote
+    }
+    fn new() -> Self
+    where
+        Self: Sized;
     fn update(&mut self, param: &(String, String)) -> Result<(), &'static str>;
+    // current value of a numeric parameter, so that the modulation matrix can change it
+    fn value(&self, _name: &str) -> Option<f64> {
+        None
+    }
     fn single_note(&self, note: &Note) -> Track;
 }
 
+pub type InstrumentFactory = Box<dyn Fn() -> Box<dyn Instrument>>;
+
+// Registry maps instrument names, as they are written in the notesheets, to their constructors.
+// Library users can register their own instruments before calling director_with_registry.
+// Parameters of a notesheet line go to the notesheet first (timing, key, arp, transpose, automate and the other
+// settings of the notesheet), only the rest reach the instrument, so instrument parameters shouldn't share their names.
+pub struct Registry {
+    factories: HashMap<String, InstrumentFactory>,
+}
+
+impl Default for Registry {
+    // registry with all of the instruments that come with wav_gen
+    fn default() -> Registry {
+        let mut registry = Registry {
+            factories: HashMap::new(),
+        };
+        registry.register("sinewave", || Box::new(SineWave::new()));
+        registry.register("simpledrum", || Box::new(Xylophone::new()));
+        registry
+    }
+}
+
+impl Registry {
+    // same as default, instruments of wav_gen are registered
+    pub fn new() -> Registry {
+        Registry::default()
+    }
+    // names are case insensitive, registering an existing name replaces the old instrument
+    pub fn register<F>(&mut self, name: &str, factory: F)
+    where
+        F: Fn() -> Box<dyn Instrument> + 'static,
+    {
+        self.factories
+            .insert(name.trim().to_ascii_lowercase(), Box::new(factory));
+    }
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.contains_key(&name.trim().to_ascii_lowercase())
+    }
+    pub fn create(&self, name: &str) -> Option<Box<dyn Instrument>> {
+        self.factories
+            .get(&name.trim().to_ascii_lowercase())
+            .map(|factory| factory())
+    }
+}
+
 // Just a sinewave
 pub struct SineWave {
     freq_mod: f64,
@@ -122,15 +316,26 @@ impl Instrument for SineWave {
             }
             _ => return Err("setting an unexisting parameter"),
         }
-        return Ok(());
+        Ok(())
+    }
+    fn value(&self, name: &str) -> Option<f64> {
+        match name {
+            "freq_mod" => Some(self.freq_mod),
+            "volume" => Some(self.volume),
+            _ => None,
+        }
     }
 
     fn single_note(&self, note: &Note) -> Track {
+        if note.is_modulated() {
+            return self.modulated_note(note);
+        }
         let mut freq = note.freq * self.freq_mod;
         // this truncates sine a bit so that it ends with 0
         let length = ((note.leng) * 2.0 * freq).trunc() / 2.0 / freq;
         freq *= 2.0 * std::f64::consts::PI;
-        let loud = note.loud * self.volume;
+        // a sinewave has no timbre to speak of, so velocity only changes the amplitude
+        let loud = note.amplitude() * self.volume;
         let mut target_vector: Vec<f64> = Vec::new();
         let times = math::linspace_from_n(0., length, Track::time_to_sample_index(length));
         for i in times {
@@ -139,7 +344,7 @@ impl Instrument for SineWave {
         let mut note_track = Track {
             track: target_vector,
             starting_sample_index: Track::time_to_sample_index(note.time),
-            loudness: 1.,
+            loudness: Gain::Constant(1.),
         };
         track::LinearFadeInOut::out_l(length / 100.).apply(&mut note_track);
         track::LinearFadeInOut::in_l(length / 100.).apply(&mut note_track);
@@ -147,6 +352,27 @@ impl Instrument for SineWave {
     }
 }
 
+impl SineWave {
+    // legato, bent and vibrating notes keep one oscillator, whose phase follows the changes of the frequency
+    fn modulated_note(&self, note: &Note) -> Track {
+        let loud = note.amplitude() * self.volume;
+        let samples = Track::time_to_sample_index(note.leng);
+        let target_vector: Vec<f64> = note
+            .phases(self.freq_mod, 0., samples)
+            .into_iter()
+            .map(|phase| loud * phase.sin())
+            .collect();
+        let mut note_track = Track {
+            track: target_vector,
+            starting_sample_index: Track::time_to_sample_index(note.time),
+            loudness: Gain::Constant(1.),
+        };
+        track::LinearFadeInOut::out_l(note.leng / 100.).apply(&mut note_track);
+        track::LinearFadeInOut::in_l(note.leng / 100.).apply(&mut note_track);
+        note_track
+    }
+}
+
 pub struct Xylophone {
     volume: f64,
     clickiness: f64,
@@ -176,39 +402,72 @@ impl Instrument for Xylophone {
                 }
             }
             "clickiness" => {
-                self.volume = match param.1.parse::<f64>() {
+                self.clickiness = match param.1.parse::<f64>() {
                     Ok(val) => val,
                     Err(_) => return Err("failed parsing str to f64 in clickiness"),
                 }
             }
             _ => return Err("setting an unexisting parameter"),
         }
-        return Ok(());
+        Ok(())
+    }
+    fn value(&self, name: &str) -> Option<f64> {
+        match name {
+            "freq_mod" => Some(self.freq_mod),
+            "volume" => Some(self.volume),
+            "clickiness" => Some(self.clickiness),
+            _ => None,
+        }
     }
     fn single_note(&self, note: &Note) -> Track {
+        // harder hits spread the frequencies wider, making the sound more clicky
+        let spread = 0.2 + 0.2 * note.loud;
         let mut freq_list: [f64; 101] = [0.; 101];
-        for i in 0..101 {
+        for (i, freq) in freq_list.iter_mut().enumerate() {
             // i - 50 / 100 is cool
             // just i is a bit curser
-            freq_list[i] =
-                note.freq * self.freq_mod * 2. * std::f64::consts::PI * (1.02 - i as f64 / 250.);
+            *freq = note.freq
+                * self.freq_mod
+                * 2.
+                * std::f64::consts::PI
+                * (1.02 - spread * i as f64 / 100.);
         }
         let t0 = note.time - self.clickiness;
         let t1 = note.time + self.clickiness;
+        if note.is_modulated() {
+            return self.modulated_note(note, spread, t0, t1);
+        }
 
         let mut target_vector: Vec<f64> = Vec::new();
         let times = math::linspace_from_n(t0, t1, Track::time_to_sample_index(t1 - t0));
         for i in times {
             let mut temp_val = 0.0_f64;
             for freq in freq_list {
-                temp_val = temp_val + ((freq * (i - t0)).sin()) as f64;
+                temp_val += (freq * (i - t0)).sin();
             }
-            target_vector.push(temp_val * note.loud * self.volume);
+            target_vector.push(temp_val * note.amplitude() * self.volume);
+        }
+        Track {
+            track: target_vector,
+            starting_sample_index: Track::time_to_sample_index(t0),
+            loudness: Gain::Constant(1.),
         }
+    }
+}
+
+impl Xylophone {
+    // every partial follows the pitch of the note, as a multiple of the phase of the main frequency
+    fn modulated_note(&self, note: &Note, spread: f64, t0: f64, t1: f64) -> Track {
+        let ratios: Vec<f64> = (0..101).map(|i| 1.02 - spread * i as f64 / 100.).collect();
+        let target_vector: Vec<f64> = note
+            .phases(self.freq_mod, t0 - note.time, Track::time_to_sample_index(t1 - t0))
+            .into_iter()
+            .map(|phase| ratios.iter().map(|ratio| (ratio * phase).sin()).sum::<f64>() * note.amplitude() * self.volume)
+            .collect();
         Track {
             track: target_vector,
             starting_sample_index: Track::time_to_sample_index(t0),
-            loudness: 1.0,
+            loudness: Gain::Constant(1.),
         }
     }
 }
@@ -0,0 +1,60 @@
+// Key signatures, such as "g major", "bb minor" or "d dorian".
+// Tones without their own accidental get the one from the key signature,
+// and accidentals written in a bar last until the end of the bar (see harmonics::Accidentals).
+
+// semitones of the degrees of every mode, counted from the tonic
+const MODES: [(&str, [i64; 7]); 9] = [
+    ("major", [0, 2, 4, 5, 7, 9, 11]),
+    ("ionian", [0, 2, 4, 5, 7, 9, 11]),
+    ("dorian", [0, 2, 3, 5, 7, 9, 10]),
+    ("phrygian", [0, 1, 3, 5, 7, 8, 10]),
+    ("lydian", [0, 2, 4, 6, 7, 9, 11]),
+    ("mixolydian", [0, 2, 4, 5, 7, 9, 10]),
+    ("minor", [0, 2, 3, 5, 7, 8, 10]),
+    ("aeolian", [0, 2, 3, 5, 7, 8, 10]),
+    ("locrian", [0, 1, 3, 5, 6, 8, 10]),
+];
+
+// semitones from C of the tone names C, D, E, F, G, A, B
+const NATURALS: [i64; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+#[derive(Clone, Copy, Default)]
+pub struct KeySignature {
+    // shift in semitones of every tone name, in the order C, D, E, F, G, A, B
+    accidentals: [f64; 7],
+}
+
+impl KeySignature {
+    // "g major", "f# minor", "eb dorian", "c" (major is implied)
+    pub fn parse(s: &str) -> Option<KeySignature> {
+        let mut split = s.split_whitespace();
+        let tonic = split.next()?;
+        let mode = split.next().unwrap_or("major");
+        if split.next().is_some() {
+            return None;
+        }
+        let letter = "cdefgab".find(tonic.get(0..1)?)?;
+        let shift = match &tonic[1..] {
+            "" => 0,
+            "#" | "sharp" => 1,
+            "b" | "flat" => -1,
+            _ => return None,
+        };
+        let degrees = MODES.iter().find(|(name, _)| *name == mode)?.1;
+        let tonic = NATURALS[letter] + shift;
+
+        // every tone name is a degree of the scale, counted from the tonic's name
+        let mut accidentals = [0.; 7];
+        for (degree, semitones) in degrees.iter().enumerate() {
+            let name = (letter + degree) % 7;
+            let distance = (tonic + semitones - NATURALS[name]).rem_euclid(12);
+            accidentals[name] = if distance > 6 { distance - 12 } else { distance } as f64;
+        }
+        Some(KeySignature { accidentals })
+    }
+
+    // shift in semitones of a tone name, C being 0 and B being 6
+    pub fn accidental(&self, name: usize) -> f64 {
+        self.accidentals[name]
+    }
+}
@@ -0,0 +1,56 @@
+// Legato plays a notesheet as a monophonic line: a note that starts before the previous one ends
+// (or right when it ends) continues the same sound instead of starting a new one, and the pitch slides
+// to the new note for the portamento time. Chords are not played, the latest note always takes over.
+
+// settings, global or per notesheet:
+// legato: on or off (default)
+// portamento: time of the slide in seconds, 0 by default (the pitch changes at once, without a new attack)
+
+use crate::instruments::Note;
+
+#[derive(Clone, Default)]
+pub struct Legato {
+    on: bool,
+    portamento: f64,
+}
+
+impl Legato {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        let value = param.1.trim();
+        match param.0.as_str() {
+            "legato" => {
+                self.on = match value {
+                    "on" | "true" | "yes" => true,
+                    "off" | "false" | "no" => false,
+                    _ => return Err("legato should be on or off"),
+                }
+            }
+            "portamento" => {
+                self.portamento = match value.parse::<f64>() {
+                    Ok(val) if val >= 0. => val,
+                    _ => return Err("portamento should be a time in seconds, such as 0.05"),
+                }
+            }
+            _ => return Err("setting an unexisting parameter"),
+        }
+        Ok(())
+    }
+
+    pub fn apply(&self, mut notes: Vec<Note>) -> Vec<Note> {
+        if !self.on {
+            return notes;
+        }
+        notes.sort_by(|a, b| a.time().total_cmp(&b.time()));
+        let mut phrases: Vec<Note> = Vec::new();
+        for note in notes {
+            match phrases.last_mut() {
+                // notes written one after another touch, rounding can leave a tiny gap
+                Some(phrase) if note.time() <= phrase.time() + phrase.leng() + 1e-6 => {
+                    phrase.glide_to(&note, self.portamento)
+                }
+                _ => phrases.push(note),
+            }
+        }
+        phrases
+    }
+}
@@ -1,97 +1,262 @@
+pub mod arpeggio;
+pub mod arrange;
+pub mod automation;
+pub mod chords;
+pub mod effects;
+pub mod groove;
 pub mod harmonics;
+pub mod humanize;
 mod math;
+pub mod presets;
+pub mod scala;
+pub mod source;
 #[cfg(test)]
 mod tests;
+pub mod tempo;
 pub mod track;
+pub mod transform;
+pub mod tuning;
+pub mod variables;
 pub mod wave_data;
 
 // notesheet should be passed to harmonics, then they give the note structs
 // then the orchestrator dispenses note-vectors to the instruments
 // and then they return sound data, which is passed to WaveData, which generates .wav file
 pub mod instruments;
+pub mod keys;
+pub mod legato;
+pub mod mixer;
+pub mod vibrato;
+pub mod modulation;
 
 
-use crate::harmonics::MakeNote;
 use crate::track::Track;
 use crate::wave_data::WaveData;
 use std::ffi::OsString;
 use std::fs;
 use std::io::Write;
-use instruments::InstrumentList;
-use instruments::Instrument;
-
-// black magic that makes BufRead work
-use std::io::prelude::*;
-use std::io::BufReader;
+use instruments::Registry;
+use presets::PresetLibrary;
+use source::{Line, Source};
+use std::collections::{HashMap, VecDeque};
 
 
 
 // .wavg file interpretator and main routine
 
 pub fn director(wavg_filename: &OsString) -> std::io::Result<()> {
-    let file = fs::File::open(wavg_filename)?;
-    let file = BufReader::new(file);
-    let mut counter: i64 = 0;
-    let mut player: InstrumentList = InstrumentList::None;
+    director_with_registry(wavg_filename, &Registry::default())
+}
+
+// same as director, but notesheet instrument names are resolved through the given registry
+pub fn director_with_registry(wavg_filename: &OsString, registry: &Registry) -> std::io::Result<()> {
+    director_with_options(wavg_filename, registry, &Options::default())
+}
+
+// what the command line asks for besides the song itself
+#[derive(Default)]
+pub struct Options {
+    // mixer channels to be played alone, and not to be played
+    pub solo: Vec<String>,
+    pub mute: Vec<String>,
+    // every mixer channel is written to a file of its own as well
+    pub stems: bool,
+}
+
+// same as director_with_registry, with the options of the command line
+pub fn director_with_options(wavg_filename: &OsString, registry: &Registry, options: &Options) -> std::io::Result<()> {
+    // the song with its included files
+    let source = Source::read(std::path::Path::new(wavg_filename))?;
+    let mut player: Option<String> = None;
     let mut player_pars: Vec<(String, String)> = Vec::new(); 
+    // kind of notes the instrument of the notesheet expects, asked once per notesheet
+    let mut note_type: Option<harmonics::NoteType> = None;
     let mut notes: Vec<instruments::Note> = Vec::new();
+    let mut sheet_pars: NotesheetParameters = NotesheetParameters::new_default();
     let mut global_pars: GlobalParameters = GlobalParameters::new_default();
-    let mut track: Track = track::Track::new();
-
-    fn cut_with_colon(split: &str, counter: i64) -> (String, String) {
+    let mut mixer = mixer::Mixer::default();
+    // lines of the mixer section are being read
+    let mut mixing = false;
+    let mut presets: PresetLibrary = PresetLibrary::default();
+    // number of recorded notesheets
+    let mut recorded: u64 = 0;
+    // highest bar number with notes written so far
+    let mut latest_bar: i64 = 0;
+    let mut dynamics: harmonics::Dynamics = harmonics::Dynamics::default();
+    let mut sections: HashMap<String, arrange::Section> = HashMap::new();
+    // section whose lines are being collected instead of being parsed
+    let mut defining: Option<(String, arrange::Section)> = None;
+    let mut variables = variables::Variables::default();
+    // automation of the whole song, written on lines of its own
+    let mut master = automation::Automation::default();
+    // recorded notesheets, waiting to be played
+    let mut takes: Vec<Take> = Vec::new();
+
+    fn cut_with_colon(split: &str, counter: Line) -> (String, String) {
         match split.find(':') {
             None => panic!("wavg synthax error: no colon in parameter definition at line {}", counter),
             Some(colon) => (split.get(0..colon).unwrap().trim().to_string(),split.get(colon+1..).unwrap().trim().to_string()),
         }
     }
 
-    fn unwrap_update (res: Result<(), &str>, counter: i64) {
-        match res {
-            Err(e) => panic!("you passed a wrong parameter, buddy: {e}, at line: {counter}"),
-            Ok(()) => {}
+    fn unwrap_update (res: Result<(), &str>, counter: Line) {
+        if let Err(e) = res {
+            panic!("you passed a wrong parameter, buddy: {e}, at line: {counter}")
         }
     }
 
-    fn get_note_type (instrument: &InstrumentList, counter: i64) -> harmonics::NoteType {
-        match instrument {
-            InstrumentList::None => panic!(
+    fn get_note_type<'a> (note_type: &'a Option<harmonics::NoteType>, counter: Line) -> &'a harmonics::NoteType {
+        match note_type {
+            None => panic!(
                 "wavg synthax error: parsing notes before defining an instrument at line {counter}"),
-            InstrumentList::SineWave => harmonics::NoteType::MelodicNote,
-            InstrumentList::Xylophone => harmonics::NoteType::MelodicNote,
+            Some(note_type) => note_type,
         }
     }
 
-    let frac = |x: (i64, i64)| -> f64 {x.0 as f64 / x.1 as f64};
+    fn velocity_unwrap(s: &str, counter: Line) -> f64 {
+        match harmonics::parse_velocity(s.trim()) {
+            Some(velocity) => velocity,
+            None => panic!("wavg synthax error: {s} is not a velocity (v0..v127) or a dynamics mark (ppp..fff) at line {counter}"),
+        }
+    }
 
-    fn record<T: Instrument>(pars: &Vec<(String, String)>, notes: &Vec<instruments::Note>, counter: i64) -> track::Track {
-        let mut player: T = T::new();
-        for par in pars.clone() { 
-            unwrap_update(player.update(&par), counter);
+    // "5 p -> 8 ff" is a gradual change that starts at the beginning of bar 5 and ends at the end of bar 8
+    fn parse_ramp(s: &str, counter: Line, value: impl Fn(&str) -> f64) -> ((i64, f64), (i64, f64)) {
+        let ends: Vec<(i64, f64)> = s.split("->").map(|end| {
+            match end.split_whitespace().collect::<Vec<&str>>()[..] {
+                [bar, val] => match bar.parse::<i64>() {
+                    Ok(bar) => (bar, value(val)),
+                    Err(_) => panic!("wavg synthax error: incorrect bar number in a gradual change at line {counter}"),
+                },
+                _ => panic!("wavg synthax error: gradual change ends should look like \"bar value\" at line {counter}"),
+            }
+        }).collect();
+        if ends.len() != 2 || ends[1].0 < ends[0].0 {
+            panic!("wavg synthax error: gradual change should look like \"5 p -> 8 f\" at line {counter}");
         }
-        player.track_from_notes(&notes)
+        (ends[0], ends[1])
     }
 
+    fn number_unwrap(s: &str, counter: Line) -> f64 {
+        match s.trim().parse::<f64>() {
+            Ok(val) => val,
+            Err(_) => panic!("wavg synthax error: {s} is not a number at line {counter}"),
+        }
+    }
+
+    fn parse_time_signature(s: &str, counter: Line) -> (i64, i64) {
+        match s.trim().split_once('/').map(|(num, den)| (num.parse::<i64>(), den.parse::<i64>())) {
+            Some((Ok(num), Ok(den))) if num > 0 && den > 0 => (num, den),
+            _ => panic!("wavg synthax error: incorrect time signature {s} at line {counter}"),
+        }
+    }
+
+    // notes are placed in time as soon as they are read, so the tempo map can't change behind them
+    fn check_tempo_change(bar: i64, latest_bar: i64, counter: Line) {
+        if bar <= latest_bar {
+            panic!("wavg synthax error: tempo change at bar {bar} comes after the notes of bar {latest_bar} were written, at line {counter}. Tempo changes should come before the bars they change");
+        }
+    }
+
+    // a recorded notesheet, it's played once the whole song is read, so that the master lanes written later apply to it
+    struct Take<'a> {
+        channel: String,
+        name: String,
+        pars: Vec<(String, String)>,
+        notes: Vec<instruments::Note>,
+        sheet_pars: NotesheetParameters,
+        counter: Line<'a>,
+    }
+
+    fn record(registry: &Registry, take: &Take, master: &automation::Automation, tempo: &tempo::TempoMap) -> track::Track {
+        let (notes, counter) = (&take.notes, take.counter);
+        let mut player = match registry.create(&take.name) {
+            Some(player) => player,
+            None => panic!("wavg synthax error: instrument not found; line {counter}"),
+        };
+        for par in &take.pars {
+            unwrap_update(player.update(par), counter);
+        }
+        let modulation = &take.sheet_pars.modulation;
+        let automation = &take.sheet_pars.automation.with_master(master, |parameter| player.value(parameter).is_some());
+        let mut track = if !modulation.is_on() && automation.only_volume() {
+            player.track_from_notes(notes)
+        } else {
+            match modulation.play(player.as_mut(), notes, tempo, automation) {
+                Ok(track) => track,
+                Err(e) => panic!("wavg synthax error: {e} in the modulation matrix at line {counter}"),
+            }
+        };
+        automation.apply_volume(&mut track, tempo);
+        track
+    }
+
+    // lines waiting to be parsed with their line numbers, arrangements put the lines of their sections in front
+    let mut queue: VecDeque<(Line, String)> = source.lines().map(|(line, raw)| (line, raw.to_string())).collect();
+
     // Parser "the cursed" edition
-    for wrapped in file.lines() {
-        counter += 1;
-        let line = wrapped.unwrap().trim().to_ascii_lowercase().to_string();
+    while let Some((counter, wrapped)) = queue.pop_front() {
+        let raw = wrapped.trim().to_string();
+        // lowercasing keeps byte positions, so raw can be sliced with indices found in line
+        let line = raw.to_ascii_lowercase();
         // Commentaries and empty lines are being ignored
-        if line.get(0..1) == Some("#") || line == "" {
+        if line.get(0..1) == Some("#") || line.is_empty() {
+            continue;
+        }
+        // lines of a section are kept until it is arranged
+        if let Some((name, mut section)) = defining.take() {
+            if line == "end section" {
+                sections.insert(name, section);
+            } else if line.starts_with("section ") || line.starts_with("arrange") {
+                panic!("wavg synthax error: sections can't define or arrange other sections, line {counter}");
+            } else {
+                section.push(counter, raw);
+                defining = Some((name, section));
+            }
+            continue;
+        }
+        if variables.define(&raw, counter) {
+            continue;
+        }
+        let raw = variables.expand(&raw, counter);
+        let line = raw.to_ascii_lowercase();
+        if mixing {
+            if line == "end mixer" {
+                mixing = false;
+            } else if let Err(e) = mixer.set(&line) {
+                panic!("wavg synthax error: {e} at line {counter}");
+            }
             continue;
         }
+        // bar lines start with the bar number, the notes themselves can contain colons (tuplets)
+        let is_bar = arrange::bar_number(&line).is_some();
         match (line.find(':'), line.find(',')) {
             //keyword lines have neither colons nor commas
             (None, None) => { 
                 match line.as_str() {
                     // "record" flushes notes into instrument
                     "record" => {
-                        match player {
-                            InstrumentList::None => 
+                        match &player {
+                            None => 
                                 panic!("wavg synthax error: parsing notes before defining an instrument at line {counter}"),
-                            InstrumentList::SineWave => 
-                                track = track.mix(&mut record::<instruments::SineWave>(&player_pars, &notes, counter)),
-                            InstrumentList::Xylophone => 
-                                track = track.mix(&mut record::<instruments::Xylophone>(&player_pars, &notes, counter)),    
+                            Some(name) => {
+                                let notes = match harmonics::merge_ties(notes) {
+                                    Ok(notes) => notes,
+                                    Err(e) => panic!("wavg synthax error: {e}, in the notes recorded at line {counter}"),
+                                };
+                                let notes = sheet_pars.transform.apply(notes, &global_pars.tempo);
+                                let notes = sheet_pars.legato.apply(notes);
+                                let notes = sheet_pars.humanize.apply(notes, recorded);
+                                takes.push(Take {
+                                    channel: sheet_pars.channel.clone().unwrap_or(name.clone()),
+                                    name: name.clone(),
+                                    pars: player_pars.clone(),
+                                    notes,
+                                    sheet_pars: sheet_pars.clone(),
+                                    counter,
+                                });
+                                recorded += 1;
+                            }
                         }
                         notes = Vec::new();
                     }
@@ -100,27 +265,42 @@ pub fn director(wavg_filename: &OsString) -> std::io::Result<()> {
                     _ => panic!("wavg synthax error: not a keyword at line {counter}"),
                 }
             }
-            //notebar lines don't have colons
-            (None, Some(first_comma_pos)) => {
+            //notebar lines start with a number
+            (_, Some(first_comma_pos)) if is_bar => {
                 let bar_index = match line.get(..first_comma_pos).unwrap().trim().parse::<i64>() {
                     Ok(n) => n,
                     Err(_) => panic!("wavg synthax error: incorrect bar number at line {counter}"),
                 };
-                let bar_timing = (bar_index - 1) as f64 * 4. * frac(global_pars.time_signature);
+                let bar_timing = global_pars.tempo.bar_position(bar_index);
+                latest_bar = latest_bar.max(bar_index);
 
-                match get_note_type(&player, counter) {
+                match get_note_type(&note_type, counter) {
                     harmonics::NoteType::MelodicNote => {
-                        for element in line.get(first_comma_pos + 1..).unwrap().split(',') {
-                            notes.push(
-                                harmonics::MelodicNote::make_note(
-                                    element.trim(),
-                                    Vec::from([global_pars.beats_per_minute, bar_timing])
-                                )
-                            );
+                        // not lowercased, chord symbols are case sensitive
+                        let parsed = harmonics::parse_bar(
+                            raw.get(first_comma_pos + 1..).unwrap(),
+                            &harmonics::NoteContext {
+                                tempo: &global_pars.tempo,
+                                bar_timing,
+                                dynamics: &dynamics,
+                                sequential: sheet_pars.sequential,
+                                voicing: &sheet_pars.voicing,
+                                tuning: &global_pars.tuning,
+                                key: &sheet_pars.key,
+                                groove: &sheet_pars.groove,
+                                arp: &sheet_pars.arp,
+                                vibrato: &sheet_pars.vibrato,
+                                transform: &sheet_pars.transform,
+                            },
+                        );
+                        match parsed {
+                            Ok(parsed) => notes.extend(parsed),
+                            Err(e) => panic!("wavg synthax error: {e} at line {counter}"),
                         }
                     }
                 }
             }
+            (None, Some(_)) => panic!("wavg synthax error: incorrect bar number at line {counter}"),
             // config lines have colons, commas are optional
             (Some(first_colon), first_comma_option) => {
                 match line.get(0..first_colon).unwrap().trim() 
@@ -132,55 +312,237 @@ pub fn director(wavg_filename: &OsString) -> std::io::Result<()> {
                             Some(val) => val,
                         };
 
-                        match line.get(first_colon + 1..first_comma).unwrap().trim() 
-                        {
-                            "sinewave" => player = InstrumentList::SineWave,
-                            "simpledrum" => player = InstrumentList::Xylophone,
-                            _ => panic!("wavg synthax error: instrument not found; line {counter}"),
-                        }
+                        let name = line.get(first_colon + 1..first_comma).unwrap().trim();
+                        note_type = match registry.create(name) {
+                            Some(instrument) => Some(instrument.note_type()),
+                            None => panic!("wavg synthax error: instrument not found; line {counter}"),
+                        };
+                        player = Some(name.to_string());
+                        player_pars = Vec::new();
+                        // notesheets start with the global settings
+                        sheet_pars = global_pars.sheet.clone();
+                        dynamics = global_pars.dynamics.clone();
+                        let mut pars: Vec<(String, String)> = Vec::new();
 
                         // else, there should be instrument config
                         for entry in line.get(first_comma..).unwrap().split(',') {
                             // if this was just an instrument declaration, we can go on
-                            if entry == "" {continue;}
-                            player_pars.push(cut_with_colon(entry, counter));
+                            if entry.is_empty() {continue;}
+                            pars.push(cut_with_colon(entry, counter));
+                        }
+                        let pars = match presets.expand(name, pars) {
+                            Ok(pars) => pars,
+                            Err(e) => panic!("wavg synthax error: {e} at line {counter}"),
+                        };
+                        // parameters that are not about the notesheet itself go to the instrument
+                        for par in pars {
+                            if NotesheetParameters::is_setting(&par.0) {
+                                unwrap_update(sheet_pars.update(&par), counter);
+                            } else {
+                                player_pars.push(par);
+                            }
+                        }
+                    }
+                    // "mixer:" starts the mixer section, its lines set up the channels until "end mixer"
+                    "mixer" => {
+                        if !line[first_colon + 1..].trim().is_empty() {
+                            panic!("wavg synthax error: mixer lines start on the next line, line {counter}");
+                        }
+                        mixing = true;
+                    }
+                    // "section verse:" starts a section, its lines are kept until "end section",
+                    // "section verse: 8 bars" also plays the bars of rest that are not written at its end
+                    section if section.starts_with("section ") => {
+                        let length = match line[first_colon + 1..].trim() {
+                            "" => None,
+                            rest => match rest.strip_suffix("bars").or(rest.strip_suffix("bar")).map(|bars| bars.trim().parse::<i64>()) {
+                                Some(Ok(bars)) if bars > 0 => Some(bars),
+                                _ => panic!("wavg synthax error: section should start with \"section name:\" or \"section name: N bars\", its lines start on the next line, line {counter}"),
+                            },
+                        };
+                        defining = Some((section["section ".len()..].trim().to_string(), arrange::Section::new(length)));
+                    }
+                    // "Arrange: intro verse x2 chorus" plays the sections one after another from bar 1,
+                    // "Arrange at bar 9: ..." from bar 9
+                    arrangement if arrangement.starts_with("arrange") => {
+                        let first_bar = match arrangement["arrange".len()..].trim() {
+                            "" => 1,
+                            rest => match rest.strip_prefix("at bar").map(|bar| bar.trim().parse::<i64>()) {
+                                Some(Ok(bar)) if bar > 0 => bar,
+                                _ => panic!("wavg synthax error: arrangement should start with \"Arrange:\" or \"Arrange at bar N:\" at line {counter}"),
+                            },
+                        };
+                        let order = arrange::parse_arrangement(&line[first_colon + 1..], counter);
+                        for played in arrange::arrange(&sections, &order, first_bar, counter).into_iter().rev() {
+                            queue.push_front(played);
+                        }
+                    }
+                    // "At bar 17: bpm 140, time_signature 3/4" changes the tempo map from that bar on
+                    at_bar if at_bar.starts_with("at bar") => {
+                        let bar = match at_bar["at bar".len()..].trim().parse::<i64>() {
+                            Ok(bar) if bar > 0 => bar,
+                            _ => panic!("wavg synthax error: incorrect bar number at line {counter}"),
+                        };
+                        check_tempo_change(bar, latest_bar, counter);
+                        for entry in line.get(first_colon + 1..).unwrap().split(',') {
+                            match entry.split_whitespace().collect::<Vec<&str>>()[..] {
+                                ["bpm" | "beatsperminute", bpm] => global_pars.tempo.set_bpm(bar, number_unwrap(bpm, counter)),
+                                ["time_signature", fraction] => global_pars.tempo.set_time_signature(bar, parse_time_signature(fraction, counter)),
+                                _ => panic!("wavg synthax error: expected \"bpm N\" or \"time_signature N/M\" at line {counter}"),
+                            }
                         }
                     }
                     // if not a notesheet, then a global config line
                     _ => {
-                        for entry in line.split(',') {
-                            unwrap_update(global_pars.update(&cut_with_colon(entry, counter)), counter);
+                        for entry in raw.split(',') {
+                            let (key, value) = cut_with_colon(entry, counter);
+                            let key = key.to_ascii_lowercase();
+                            match key.as_str() {
+                                // file names are case sensitive, and relative to the file they are written in
+                                "presets" => {
+                                    let path = counter.dir().join(value);
+                                    if let Err(e) = presets.load_file(&path) {
+                                        panic!("wavg synthax error: can't read the presets {}: {e} at line {counter}", path.display());
+                                    }
+                                }
+                                "scale" => {
+                                    let path = counter.dir().join(value);
+                                    match scala::Scale::load(&path) {
+                                        Ok(scale) => global_pars.tuning.set_scale(scale),
+                                        Err(e) => panic!("wavg synthax error: can't read the scale {}: {e} at line {counter}", path.display()),
+                                    }
+                                }
+                                "keyboard_map" => {
+                                    let path = counter.dir().join(value);
+                                    match scala::KeyboardMap::load(&path) {
+                                        Ok(map) => global_pars.tuning.set_keyboard_map(map),
+                                        Err(e) => panic!("wavg synthax error: can't read the keyboard map {}: {e} at line {counter}", path.display()),
+                                    }
+                                }
+                                // dynamics apply to the following bars of the current notesheet,
+                                // before the first notesheet every notesheet starts with them
+                                "dynamics" => {
+                                    let dynamics = if player.is_none() { &mut global_pars.dynamics } else { &mut dynamics };
+                                    dynamics.set(velocity_unwrap(&value.to_ascii_lowercase(), counter));
+                                }
+                                "crescendo" | "diminuendo" => {
+                                    let ((start_bar, from), (end_bar, to)) = parse_ramp(&value.to_ascii_lowercase(), counter, |mark| velocity_unwrap(mark, counter));
+                                    if (key == "crescendo") != (to > from) {
+                                        panic!("wavg synthax error: {key} goes the wrong way at line {counter}");
+                                    }
+                                    let dynamics = if player.is_none() { &mut global_pars.dynamics } else { &mut dynamics };
+                                    dynamics.add_hairpin(global_pars.tempo.bar_position(start_bar), global_pars.tempo.bar_position(end_bar + 1), from, to);
+                                }
+                                // "9 120 -> 12 160" goes from 120 bpm at the beginning of bar 9 to 160 bpm at the end of bar 12
+                                "accelerando" | "ritardando" => {
+                                    let ((start_bar, from), (end_bar, to)) = parse_ramp(&value, counter, |bpm| number_unwrap(bpm, counter));
+                                    if (key == "accelerando") != (to > from) {
+                                        panic!("wavg synthax error: {key} goes the wrong way at line {counter}");
+                                    }
+                                    check_tempo_change(start_bar, latest_bar, counter);
+                                    global_pars.tempo.add_ramp(start_bar, from, end_bar, to);
+                                }
+                                // lanes on lines of their own change the whole song
+                                "automate" => {
+                                    let par = (key, value.to_ascii_lowercase());
+                                    unwrap_update(master.update(&par), counter);
+                                }
+                                // tempo of the beginning of the song, use "At bar N:" for the changes
+                                "bpm" | "beatsperminute" | "time_signature" if latest_bar > 0 => {
+                                    check_tempo_change(1, latest_bar, counter);
+                                }
+                                _ => {
+                                    let par = (key, value.to_ascii_lowercase());
+                                    // notesheet settings change the current notesheet, before the first notesheet
+                                    // they become the default for all of them
+                                    if NotesheetParameters::is_setting(&par.0) {
+                                        unwrap_update(sheet_pars.update(&par), counter);
+                                        if player.is_none() {
+                                            unwrap_update(global_pars.sheet.update(&par), counter);
+                                        }
+                                    } else {
+                                        unwrap_update(global_pars.update(&par), counter);
+                                    }
+                                }
+                            }
                         };
                     }
                 }
             }
         }
     }
-    track.start_with_silence();
-    track.normalize();
-    track.apply_loudness();
+    if let Some((name, _)) = defining {
+        panic!("wavg synthax error: section {name} is not ended with \"end section\"");
+    }
+    if mixing {
+        panic!("wavg synthax error: mixer section is not ended with \"end mixer\"");
+    }
+    for take in &takes {
+        mixer.add(&take.channel, record(registry, take, &master, &global_pars.tempo));
+    }
+    // the command line is not a part of the file, so its mistakes are returned rather than panicking
+    if let Err(e) = mixer.check_command_line(&options.solo, &options.mute) {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, e));
+    }
+    let mut mixdown = match mixer.mixdown(&options.solo, &options.mute, options.stems) {
+        Ok(mixdown) => mixdown,
+        Err(e) => panic!("wavg synthax error: {e}"),
+    };
+    before_master(&mut mixdown, &master, &global_pars.tempo);
+    let mut tracks: Vec<&mut Track> = mixdown
+        .song
+        .iter_mut()
+        .chain(mixdown.stems.iter_mut().flat_map(|(_, stem)| stem.iter_mut()))
+        .collect();
+    // the effects of the master are linear, so the stems still add up to the song
+    for track in tracks.iter_mut() {
+        for effect in &mixdown.master {
+            effect.apply(track);
+        }
+    }
+    // everything is normalized together, so that the panning stays and the stems add up to the song
+    let peak = tracks.iter().map(|track| track.peak()).fold(0., f64::max);
+    for track in tracks.iter_mut() {
+        track.normalize(peak);
+        track.apply_loudness();
+    }
 
-    let mut this_file_name = format!("{}.wav", global_pars.name);
+    global_pars.write_wav_file(&free_file_name(&global_pars.name), &mixdown.song);
+    for (channel, stem) in &mixdown.stems {
+        global_pars.write_wav_file(&free_file_name(&format!("{} {channel}", global_pars.name)), stem);
+    }
+    Ok(())
+}
+
+// the master volume lane on the song and the stems,
+// stems start with the song and end with it, so that they line up in other programs,
+// they are padded before the master effects, so that these ring out in them as they do in the song
+pub(crate) fn before_master(mixdown: &mut mixer::Mixdown, master: &automation::Automation, tempo: &tempo::TempoMap) {
+    let mut tracks: Vec<&mut Track> = mixdown
+        .song
+        .iter_mut()
+        .chain(mixdown.stems.iter_mut().flat_map(|(_, stem)| stem.iter_mut()))
+        .collect();
+    for track in tracks.iter_mut() {
+        track.start_with_silence();
+        master.apply_volume(track, tempo);
+        track.apply_loudness();
+    }
+    let ending = tracks.iter().map(|track| track.ending_sample_index()).max().unwrap_or(0);
+    for track in tracks.iter_mut() {
+        track.end_with_silence(ending);
+    }
+}
+
+// files are never overwritten, "name(0).wav" is taken when "name.wav" exists
+fn free_file_name(name: &str) -> String {
+    let mut this_file_name = format!("{}.wav", name);
     let mut this_file_index = 0; 
     while std::path::Path::new(&this_file_name).exists() {
-        this_file_name = format!("{}({}).wav", global_pars.name, this_file_index); 
+        this_file_name = format!("{}({}).wav", name, this_file_index); 
         this_file_index += 1;
     }
-    global_pars.name = this_file_name;
-
-    match global_pars.bits_per_sample {
-        8 => {
-            global_pars.generate_wav_file::<Vec<u8>>(&track);
-        },
-        16 => {
-            global_pars.generate_wav_file::<Vec<i16>>(&track);
-        },
-        32 => {
-            global_pars.generate_wav_file::<Vec<f32>>(&track);
-        },
-        _ => panic!("unknown bits per sample setting: {}. Try 8, 16 or 32", global_pars.bits_per_sample)
-    } 
-    Ok(())
+    this_file_name
 }
 
 
@@ -188,8 +550,12 @@ struct GlobalParameters {
     name: String,
     sample_rate: u32,
     bits_per_sample: u16,
-    beats_per_minute: f64,
-    time_signature: (i64, i64),
+    // bpm and time signature, with their changes
+    tempo: tempo::TempoMap,
+    tuning: tuning::Tuning,
+    // defaults for every notesheet
+    sheet: NotesheetParameters,
+    dynamics: harmonics::Dynamics,
 }
 
 impl GlobalParameters {
@@ -198,15 +564,27 @@ impl GlobalParameters {
             name: "wave_generator_generated.wav".to_string(),
             sample_rate: 44100,
             bits_per_sample: 16,
-            beats_per_minute: 120.,
-            time_signature: (4, 4),
+            tempo: tempo::TempoMap::default(),
+            tuning: tuning::Tuning::default(),
+            sheet: NotesheetParameters::new_default(),
+            dynamics: harmonics::Dynamics::default(),
         }
     }
 
-    fn generate_wav_file<T: WaveData> (&self, datatrack: &track::Track) {
+    fn write_wav_file(&self, file_name: &str, channels: &[track::Track]) {
+        match self.bits_per_sample {
+            8 => self.generate_wav_file::<Vec<u8>>(file_name, channels),
+            16 => self.generate_wav_file::<Vec<i16>>(file_name, channels),
+            32 => self.generate_wav_file::<Vec<f32>>(file_name, channels),
+            _ => panic!("unknown bits per sample setting: {}. Try 8, 16 or 32", self.bits_per_sample)
+        }
+    }
+
+    fn generate_wav_file<T: WaveData> (&self, file_name: &str, channels: &[track::Track]) {
         let mut data = T::new();
-        data.generate_from_wave(&datatrack.track, self.sample_rate);
-        let cfg: WavConfig<T> = WavConfig::new(self.name.clone(), 1, self.sample_rate, data);
+        let waves: Vec<&[f64]> = channels.iter().map(|channel| channel.track.as_slice()).collect();
+        data.generate_from_channels(&waves, self.sample_rate);
+        let cfg: WavConfig<T> = WavConfig::new(file_name.to_string(), channels.len() as u16, self.sample_rate, data);
         gen_wav_file(cfg);
     }
 
@@ -216,18 +594,105 @@ impl GlobalParameters {
             "samplerate" => self.sample_rate = param.1.trim().parse::<u32>().unwrap(),
             "bitspersample" => self.bits_per_sample = param.1.trim().parse::<u16>().unwrap(),
             "beatsperminute" | "bpm" => {
-                self.beats_per_minute = param.1.trim().parse::<f64>().unwrap()
+                self.tempo.set_initial_bpm(param.1.trim().parse::<f64>().unwrap())
             }
             "time_signature" => {
                 let fraction: Vec<i64> = param.1.trim().split('/').map(|s| s.parse::<i64>().unwrap()).collect();
-                self.time_signature = (fraction[0], fraction[1]);
+                self.tempo.set_initial_time_signature((fraction[0], fraction[1]));
             }
+            // errors go back to the caller, so that the line is reported
+            "tuning" | "a4" | "tonic" => self.tuning.update(&(param.0.to_ascii_lowercase(), param.1.trim().to_string()))?,
             _ => {
                 return Err(param.0.as_str());
             }
         }
         
-        return Ok(());
+        Ok(())
+    }
+}
+
+// parameters of a notesheet that change how its bars are read, rather than how the instrument sounds
+#[derive(Clone)]
+struct NotesheetParameters {
+    sequential: bool,
+    voicing: chords::Voicing,
+    key: keys::KeySignature,
+    groove: groove::Groove,
+    humanize: humanize::Humanize,
+    transform: transform::Transform,
+    arp: arpeggio::Arpeggio,
+    legato: legato::Legato,
+    vibrato: vibrato::Vibrato,
+    modulation: modulation::Modulation,
+    automation: automation::Automation,
+    // mixer channel of the notesheet, the instrument name when it's not given
+    channel: Option<String>,
+}
+
+impl NotesheetParameters {
+    fn new_default() -> NotesheetParameters {
+        NotesheetParameters {
+            sequential: false,
+            voicing: chords::Voicing::default(),
+            key: keys::KeySignature::default(),
+            groove: groove::Groove::default(),
+            humanize: humanize::Humanize::default(),
+            transform: transform::Transform::default(),
+            arp: arpeggio::Arpeggio::default(),
+            legato: legato::Legato::default(),
+            vibrato: vibrato::Vibrato::default(),
+            modulation: modulation::Modulation::default(),
+            automation: automation::Automation::default(),
+            channel: None,
+        }
+    }
+
+    // settings that belong to the notesheet rather than to its instrument or the whole song
+    fn is_setting(key: &str) -> bool {
+        let key = key.to_ascii_lowercase();
+        matches!(
+            key.as_str(),
+            "timing" | "key" | "chord_octave" | "inversion" | "voicing"
+                | "swing" | "groove" | "groove_grid" | "groove_offsets" | "groove_velocities"
+                | "humanize" | "humanize_timing" | "humanize_length" | "humanize_velocity" | "humanize_detune" | "humanize_seed"
+                | "transpose" | "octave" | "invert" | "retrograde" | "time_scale"
+                | "arp" | "arp_rate" | "arp_octaves" | "grid_step" | "legato" | "portamento"
+                | "vibrato" | "vibrato_rate" | "vibrato_delay" | "mod" | "automate" | "channel"
+        ) || modulation::is_source(&key)
+    }
+
+    // errors go back to the caller, so that the line is reported
+    fn update<'a>(&mut self, param: &'a (String, String)) -> Result<(), &'a str> {
+        match param.0.to_ascii_lowercase().as_str() {
+            "timing" => {
+                self.sequential = match param.1.trim() {
+                    "sequential" => true,
+                    "explicit" => false,
+                    _ => return Err("timing should be sequential or explicit"),
+                }
+            }
+            "key" => {
+                self.key = match keys::KeySignature::parse(&param.1) {
+                    Some(key) => key,
+                    None => return Err("key should be something like g major, bb minor or d dorian"),
+                }
+            }
+            "chord_octave" | "inversion" | "voicing" => self.voicing.update(param)?,
+            "swing" | "groove" | "groove_grid" | "groove_offsets" | "groove_velocities" => self.groove.update(param)?,
+            "humanize" | "humanize_timing" | "humanize_length" | "humanize_velocity" | "humanize_detune" | "humanize_seed" => self.humanize.update(param)?,
+            "transpose" | "octave" | "invert" | "retrograde" | "time_scale" => self.transform.update(param)?,
+            "arp" | "arp_rate" | "arp_octaves" | "grid_step" => self.arp.update(param)?,
+            "legato" | "portamento" => self.legato.update(param)?,
+            "vibrato" | "vibrato_rate" | "vibrato_delay" => self.vibrato.update(param)?,
+            key if key == "mod" || modulation::is_source(key) => self.modulation.update(param)?,
+            "automate" => self.automation.update(param)?,
+            "channel" => self.channel = Some(param.1.trim().to_string()),
+            _ => {
+                return Err(param.0.as_str());
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -1,12 +1,38 @@
 use std::ffi::OsString;
-use wav_gen::director;
+use wav_gen::instruments::Registry;
+use wav_gen::{director_with_options, Options};
 
 fn main() {
     // Generating a sound to turn into a *.wav file, funniest shit I've ever seen
-    let path = std::env::args().nth(1).expect("no filename given");
+    // "wav_gen song.wavg --solo drums --mute pad --stems", solo and mute can be given more than once
+    let mut path: Option<String> = None;
+    let mut options = Options::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--solo" | "--mute" => {
+                let channel = args
+                    .next()
+                    .unwrap_or_else(|| panic!("{arg} should be followed by a mixer channel"))
+                    .to_ascii_lowercase();
+                if arg == "--solo" {
+                    options.solo.push(channel);
+                } else {
+                    options.mute.push(channel);
+                }
+            }
+            "--stems" => options.stems = true,
+            flag if flag.starts_with("--") => panic!("unknown flag {flag}, try --solo, --mute or --stems"),
+            _ => path = Some(arg),
+        }
+    }
+    let path = path.expect("no filename given");
     assert!(
         std::path::Path::new(&path).exists(),
         "given filename does not exist"
     );
-    director(&OsString::from(path)).unwrap();
+    if let Err(e) = director_with_options(&OsString::from(path), &Registry::default(), &options) {
+        eprintln!("{e}");
+        std::process::exit(1);
+    }
 }
@@ -46,10 +46,34 @@ pub fn linspace_from_n(x0: f64, x1: f64, n: usize) -> Vec<f64> {
         "Tried to create a linspace with x0 = {x0} and x1 = {x1}"
     );
 
-    let mut vector: Vec<f64> = Vec::with_capacity(n as usize);
+    let mut vector: Vec<f64> = Vec::with_capacity(n);
     let nf = (n - 1) as f64;
     for i in 0..n {
         vector.push(x0 + (x1 - x0) * i as f64 / nf);
     }
     vector
 }
+
+// small seedable pseudo random generator (splitmix64), the same seed always gives the same numbers
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    // uniformly distributed within [-1, 1)
+    pub fn symmetric(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 52) as f64 - 1.
+    }
+}
@@ -0,0 +1,250 @@
+// Mixer keeps the recorded notesheets as named channels, and sums them into the song at the end.
+// A notesheet plays on the channel of its instrument name, "channel: drums" on the notesheet line names it.
+// The channels are set up in the mixer section:
+//
+// mixer:
+// bus room: reverb 0.7, lowpass 5000, gain -3
+// drums: gain -3, pan -0.4, send room -12
+// lead: gain 2db, pan 0.3, solo, send room -6
+// pad: mute
+// master: highpass 40
+// end mixer
+//
+// gain: in decibels, 0 by default
+// pan: from -1 (left) to 1 (right), 0 by default. The song is written in stereo when any channel is panned.
+// mute: the channel is not played
+// solo: only the soloed channels are played
+// send: how loud the channel goes to a bus, in decibels, after its gain and before its pan
+//
+// Buses are channels that play what is sent to them through their chain of effects, see effects.rs.
+// They have gain, pan and mute too, and keep playing when other channels are soloed.
+// The effects of the master are applied to the whole song, before it is normalized.
+// Channels can also be soloed and muted from the command line, "wav_gen song.wavg --solo drums --mute pad".
+// "--stems" writes every played channel to a file of its own as well, after its gain and pan and through the master.
+
+use crate::effects::{parse_effect, Effect};
+use crate::track::{Gain, Track};
+use std::collections::HashMap;
+
+#[derive(Clone, Default)]
+struct Strip {
+    gain: f64,
+    pan: f64,
+    mute: bool,
+    solo: bool,
+    // (bus, level in decibels)
+    sends: Vec<(String, f64)>,
+}
+
+#[derive(Default)]
+struct Bus {
+    strip: Strip,
+    chain: Vec<Box<dyn Effect>>,
+}
+
+// tracks of the song and of the stems, one for mono or left and right for stereo
+pub struct Mixdown {
+    pub song: Vec<Track>,
+    // (channel, tracks) in the order the channels were first recorded, then the buses
+    pub stems: Vec<(String, Vec<Track>)>,
+    // effects of the master, for each of the song tracks
+    pub master: Vec<Box<dyn Effect>>,
+}
+
+#[derive(Default)]
+pub struct Mixer {
+    strips: HashMap<String, Strip>,
+    // recorded tracks with their channels, in the order they were recorded
+    tracks: Vec<(String, Track)>,
+    // in the order they are written
+    buses: Vec<(String, Bus)>,
+    master: Vec<Box<dyn Effect>>,
+}
+
+// "-3" or "-3db"
+fn parse_decibels(s: &str) -> Option<f64> {
+    s.strip_suffix("db").unwrap_or(s).parse::<f64>().ok()
+}
+
+// linear gain of decibels
+pub fn amplitude(decibels: f64) -> f64 {
+    10_f64.powf(decibels / 20.)
+}
+
+impl Mixer {
+    // a line of the mixer section, "drums: gain -3, pan -0.4, mute", "bus room: reverb 0.7" or "master: lowpass 9000"
+    pub fn set(&mut self, line: &str) -> Result<(), &'static str> {
+        let (name, settings) = match line.split_once(':') {
+            Some((name, settings)) if !name.trim().is_empty() => (name.trim(), settings),
+            _ => return Err("mixer lines look like \"drums: gain -3, pan -0.4\""),
+        };
+        let (mut strip, mut chain) = if name == "master" {
+            (None, Some(&mut self.master))
+        } else if let Some(bus) = name.strip_prefix("bus ") {
+            let bus = bus.trim().to_string();
+            if !self.buses.iter().any(|(name, _)| *name == bus) {
+                self.buses.push((bus.clone(), Bus::default()));
+            }
+            let (_, bus) = self.buses.iter_mut().find(|(name, _)| *name == bus).unwrap();
+            (Some(&mut bus.strip), Some(&mut bus.chain))
+        } else {
+            (Some(self.strips.entry(name.to_string()).or_default()), None)
+        };
+        for setting in settings.split(',').map(|setting| setting.trim()).filter(|setting| !setting.is_empty()) {
+            // effects go to the chain of buses and the master, in the order they are written
+            if let (Some(chain), Some(effect)) = (chain.as_deref_mut(), parse_effect(setting)) {
+                chain.push(effect?);
+                continue;
+            }
+            let strip = match strip.as_deref_mut() {
+                Some(strip) => strip,
+                None => return Err("the master only has effects: delay, reverb, lowpass and highpass"),
+            };
+            match setting.split_whitespace().collect::<Vec<&str>>()[..] {
+                ["gain", decibels] => {
+                    strip.gain = match parse_decibels(decibels) {
+                        Some(val) => val,
+                        None => return Err("gain should be in decibels, such as -3 or +2db"),
+                    }
+                }
+                ["pan", pan] => {
+                    strip.pan = match pan.parse::<f64>() {
+                        Ok(val) if (-1. ..=1.).contains(&val) => val,
+                        _ => return Err("pan should be from -1 (left) to 1 (right)"),
+                    }
+                }
+                ["mute"] => strip.mute = true,
+                ["solo"] => strip.solo = true,
+                ["send", bus, level] => match parse_decibels(level) {
+                    Some(level) => strip.sends.push((bus.to_string(), level)),
+                    None => return Err("send should be a bus and a level in decibels, such as \"send room -6\""),
+                },
+                _ if chain.is_some() => return Err("buses have effects (delay, reverb, lowpass, highpass), gain, pan and mute"),
+                _ => return Err("mixer channels have gain, pan, mute, solo and send"),
+            }
+        }
+        Ok(())
+    }
+
+    // track of a record block, added to the channel
+    pub fn add(&mut self, channel: &str, track: Track) {
+        self.tracks.push((channel.to_string(), track));
+    }
+
+    fn is_played(&self, name: &str) -> bool {
+        self.tracks.iter().any(|(channel, _)| channel == name)
+    }
+
+    // channels soloed and muted from the command line should be played, buses can be muted too
+    pub fn check_command_line(&self, solo: &[String], mute: &[String]) -> Result<(), String> {
+        for name in solo {
+            if !self.is_played(name) {
+                return Err(format!("--solo {name}: mixer channel {name} is not played by any notesheet"));
+            }
+        }
+        for name in mute {
+            if !self.is_played(name) && !self.buses.iter().any(|(bus, _)| bus == name) {
+                return Err(format!("--mute {name}: mixer channel {name} is not played by any notesheet"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_stereo(&self) -> bool {
+        self.strips
+            .values()
+            .chain(self.buses.iter().map(|(_, bus)| &bus.strip))
+            .any(|strip| strip.pan != 0.)
+    }
+
+    // the song, one track for mono or left and right for stereo, and the stems of the channels when asked for,
+    // solo and mute are the channels given on the command line
+    pub fn mixdown(self, solo: &[String], mute: &[String], stems: bool) -> Result<Mixdown, String> {
+        let is_bus = |name: &String| self.buses.iter().any(|(bus, _)| bus == name);
+        for name in self.strips.keys() {
+            if !self.is_played(name) {
+                return Err(format!("mixer channel {name} is not played by any notesheet"));
+            }
+        }
+        self.check_command_line(solo, mute)?;
+        for (bus, _) in self.strips.values().chain(self.buses.iter().map(|(_, bus)| &bus.strip)).flat_map(|strip| &strip.sends) {
+            if !is_bus(bus) {
+                return Err(format!("bus {bus} is sent to, but not defined"));
+            }
+        }
+        // buses play one after another, so a bus can only send to the ones written after it
+        for (i, (name, bus)) in self.buses.iter().enumerate() {
+            if bus.strip.sends.iter().any(|(to, _)| !self.buses[i + 1..].iter().any(|(bus, _)| bus == to)) {
+                return Err(format!("bus {name} can only send to the buses written after it"));
+            }
+        }
+        let soloing = !solo.is_empty() || self.strips.values().any(|strip| strip.solo);
+        let outputs = if self.is_stereo() { 2 } else { 1 };
+        let mut mixdown = Mixdown {
+            song: (0..outputs).map(|_| Track::new()).collect(),
+            stems: Vec::new(),
+            master: self.master,
+        };
+        // what the channels send to the buses
+        let mut inputs: HashMap<&str, Track> = HashMap::new();
+        for (channel, mut track) in self.tracks {
+            let strip = self.strips.get(&channel).cloned().unwrap_or_default();
+            if strip.mute || mute.contains(&channel) || (soloing && !strip.solo && !solo.contains(&channel)) {
+                continue;
+            }
+            // automation of the notesheet goes first, so that the gain of the channel can replace it
+            track.apply_loudness();
+            for (bus, level) in &strip.sends {
+                let (bus, _) = self.buses.iter().find(|(name, _)| name == bus).unwrap();
+                track.loudness = Gain::Constant(amplitude(strip.gain + level));
+                let input = inputs.entry(bus).or_default();
+                *input = input.mix(&mut track);
+            }
+            mixdown.place(&channel, &mut track, &strip, stems);
+        }
+        for (name, bus) in &self.buses {
+            // a bus that nothing is sent to stays silent, instead of playing the tail of its effects over nothing
+            let Some(mut track) = inputs.remove(name.as_str()) else {
+                continue;
+            };
+            if bus.strip.mute || mute.contains(name) {
+                continue;
+            }
+            for effect in &bus.chain {
+                effect.apply(&mut track);
+            }
+            for (to, level) in &bus.strip.sends {
+                let (to, _) = self.buses.iter().find(|(bus, _)| bus == to).unwrap();
+                track.loudness = Gain::Constant(amplitude(bus.strip.gain + level));
+                let input = inputs.entry(to).or_default();
+                *input = input.mix(&mut track);
+            }
+            mixdown.place(name, &mut track, &bus.strip, stems);
+        }
+        Ok(mixdown)
+    }
+}
+
+impl Mixdown {
+    // the track of a channel or a bus added to the song, and to its stem when they are written
+    fn place(&mut self, name: &str, track: &mut Track, strip: &Strip, stems: bool) {
+        let gain = amplitude(strip.gain);
+        // constant power panning, so that the loudness doesn't change across the field
+        let angle = (strip.pan + 1.) * std::f64::consts::PI / 4.;
+        let sides = if self.song.len() == 2 {
+            vec![angle.cos(), angle.sin()]
+        } else {
+            vec![1.]
+        };
+        if stems && !self.stems.iter().any(|(stem, _)| stem == name) {
+            self.stems.push((name.to_string(), (0..self.song.len()).map(|_| Track::new()).collect()));
+        }
+        for (i, side) in sides.into_iter().enumerate() {
+            track.loudness = Gain::Constant(gain * side);
+            self.song[i] = self.song[i].mix(track);
+            if let Some((_, stem)) = self.stems.iter_mut().find(|(stem, _)| stem == name) {
+                stem[i] = stem[i].mix(track);
+            }
+        }
+    }
+}
@@ -0,0 +1,209 @@
+// Modulation matrix routes LFOs and envelopes to the numeric parameters of the instrument,
+// so that they change along the notesheet instead of staying the same for the whole record block.
+// The parameters are taken at the start of every note, a note keeps them until it ends, so a held note
+// doesn't follow the LFO: modulation suits short and repeated notes (arpeggios, grids, drum hits) rather than
+// a long pad, vibrato and bends (see vibrato.rs) move the pitch of a note while it sounds.
+
+// settings, global or per notesheet:
+// lfo1: sine 2 is a low frequency oscillator with its shape and rate in Hz,
+// shapes are sine, tri, saw, square and sh (sample and hold, a random value every cycle)
+// lfo2: tri 1/2 is synced to the tempo, a rate written as a fraction is the length of a cycle (a half note here)
+// env1: 0.5 2 rises from 0 to 1 for 0.5 seconds since the first note of the notesheet, then falls back for 2 seconds
+// mod: lfo1 -> volume 0.3 adds the source times the amount to the written value of the parameter,
+// every "mod" entry adds a route. LFOs go from -1 to 1, envelopes from 0 to 1.
+// Sources are named lfo or env followed by a number, such as lfo1 or env2.
+
+use crate::automation::Automation;
+use crate::instruments::{Instrument, Note};
+use crate::math::Rng;
+use crate::tempo::TempoMap;
+use crate::track::Track;
+use std::collections::HashMap;
+
+#[derive(Clone, Copy)]
+enum Shape {
+    Sine,
+    Triangle,
+    Saw,
+    Square,
+    SampleAndHold,
+}
+
+#[derive(Clone, Copy)]
+enum Rate {
+    Hz(f64),
+    // length of a cycle in 1/4th
+    Synced(f64),
+}
+
+#[derive(Clone, Copy)]
+enum Source {
+    Lfo(Shape, Rate),
+    // attack and decay in seconds
+    Envelope(f64, f64),
+}
+
+#[derive(Clone)]
+struct Route {
+    source: String,
+    parameter: String,
+    amount: f64,
+}
+
+#[derive(Clone, Default)]
+pub struct Modulation {
+    sources: HashMap<String, Source>,
+    routes: Vec<Route>,
+}
+
+// keys of the settings that define sources, such as lfo1 or env2
+pub fn is_source(key: &str) -> bool {
+    let number = key.strip_prefix("lfo").or_else(|| key.strip_prefix("env"));
+    matches!(number, Some(number) if !number.is_empty() && number.chars().all(|c| c.is_ascii_digit()))
+}
+
+fn parse_lfo(value: &str) -> Option<Source> {
+    let (shape, rate) = value.split_once(char::is_whitespace)?;
+    let shape = match shape {
+        "sine" => Shape::Sine,
+        "tri" | "triangle" => Shape::Triangle,
+        "saw" => Shape::Saw,
+        "square" => Shape::Square,
+        "sh" => Shape::SampleAndHold,
+        _ => return None,
+    };
+    let rate = match rate.trim().split_once('/') {
+        Some((num, den)) => match (num.parse::<i64>(), den.parse::<i64>()) {
+            (Ok(num), Ok(den)) if num > 0 && den > 0 => Rate::Synced(4. * num as f64 / den as f64),
+            _ => return None,
+        },
+        None => match rate.trim().parse::<f64>() {
+            Ok(hz) if hz > 0. => Rate::Hz(hz),
+            _ => return None,
+        },
+    };
+    Some(Source::Lfo(shape, rate))
+}
+
+fn parse_envelope(value: &str) -> Option<Source> {
+    let (attack, decay) = value.split_once(char::is_whitespace)?;
+    match (attack.parse::<f64>(), decay.trim().parse::<f64>()) {
+        (Ok(attack), Ok(decay)) if attack >= 0. && decay >= 0. => Some(Source::Envelope(attack, decay)),
+        _ => None,
+    }
+}
+
+// "lfo1 -> volume 0.3"
+fn parse_route(value: &str) -> Option<Route> {
+    let (source, target) = value.split_once("->")?;
+    let (parameter, amount) = target.trim().split_once(char::is_whitespace)?;
+    Some(Route {
+        source: source.trim().to_string(),
+        parameter: parameter.to_string(),
+        amount: amount.trim().parse::<f64>().ok()?,
+    })
+}
+
+impl Source {
+    // value at this many seconds since the beginning of the song, start is the time of the first note
+    fn value(&self, time: f64, start: f64, tempo: &TempoMap) -> f64 {
+        match *self {
+            Source::Lfo(shape, rate) => {
+                let cycles = match rate {
+                    Rate::Hz(hz) => time * hz,
+                    Rate::Synced(length) => tempo.position(time) / length,
+                };
+                let phase = cycles.fract();
+                match shape {
+                    Shape::Sine => (2. * std::f64::consts::PI * phase).sin(),
+                    Shape::Triangle => 1. - 4. * (phase - 0.5).abs(),
+                    Shape::Saw => 2. * phase - 1.,
+                    Shape::Square if phase < 0.5 => 1.,
+                    Shape::Square => -1.,
+                    Shape::SampleAndHold => Rng::new(cycles.floor() as u64).symmetric(),
+                }
+            }
+            Source::Envelope(attack, decay) => {
+                let since = time - start;
+                if since < attack {
+                    since / attack
+                } else if decay > 0. {
+                    (1. - (since - attack) / decay).max(0.)
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+}
+
+impl Modulation {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        let key = param.0.as_str();
+        let value = param.1.trim();
+        if key == "mod" {
+            match parse_route(value) {
+                Some(route) => self.routes.push(route),
+                None => return Err("mod should route a source to a parameter, such as \"lfo1 -> volume 0.3\""),
+            }
+        } else if is_source(key) && key.starts_with("lfo") {
+            match parse_lfo(value) {
+                Some(lfo) => self.sources.insert(key.to_string(), lfo),
+                None => return Err("lfo should be a shape and a rate, such as \"sine 2\" or \"tri 1/4\""),
+            };
+        } else if is_source(key) {
+            match parse_envelope(value) {
+                Some(envelope) => self.sources.insert(key.to_string(), envelope),
+                None => return Err("env should be attack and decay in seconds, such as \"0.5 2\""),
+            };
+        } else {
+            return Err("setting an unexisting parameter");
+        }
+        Ok(())
+    }
+
+    pub fn is_on(&self) -> bool {
+        !self.routes.is_empty()
+    }
+
+    // notes played one by one, with the parameters of the instrument set at the start of every note,
+    // automated parameters take the value of their lane before the routes are added
+    pub fn play(&self, player: &mut dyn Instrument, notes: &[Note], tempo: &TempoMap, automation: &Automation) -> Result<Track, String> {
+        // written values of the parameters, before any modulation
+        let mut written: HashMap<&str, f64> = HashMap::new();
+        for lane in automation.instrument_lanes() {
+            match player.value(&lane.parameter) {
+                Some(value) => written.insert(&lane.parameter, value),
+                None => return Err(format!("{} can't be automated", lane.parameter)),
+            };
+        }
+        for route in &self.routes {
+            if !self.sources.contains_key(&route.source) {
+                return Err(format!("{} is routed, but not defined", route.source));
+            }
+            match player.value(&route.parameter) {
+                Some(value) => written.insert(&route.parameter, value),
+                None => return Err(format!("{} can't be modulated", route.parameter)),
+            };
+        }
+        let start = notes.iter().map(|note| note.time()).fold(f64::INFINITY, f64::min);
+        let mut track = Track::new();
+        for note in notes {
+            let mut values = written.clone();
+            for lane in automation.instrument_lanes() {
+                values.insert(&lane.parameter, lane.value(note.time(), tempo));
+            }
+            for route in &self.routes {
+                let source = self.sources[&route.source].value(note.time(), start, tempo);
+                *values.get_mut(route.parameter.as_str()).unwrap() += route.amount * source;
+            }
+            for (parameter, value) in values {
+                if let Err(e) = player.update(&(parameter.to_string(), value.to_string())) {
+                    return Err(e.to_string());
+                }
+            }
+            track = track.mix(&mut player.single_note(note));
+        }
+        Ok(track)
+    }
+}
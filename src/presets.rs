@@ -0,0 +1,124 @@
+// Presets are named sets of notesheet parameters for a specific instrument.
+// They are referenced in a notesheet line as "Notesheet: SineWave, preset: warm_pad",
+// and any parameters written in the notesheet line itself override the preset values.
+
+// Preset files (.wavgp) contain one preset per line:
+// warm_pad: SineWave, volume: 0.6, vibrato: 0.1
+// i.e. preset name, colon, instrument name, followed by the parameters, same as in a notesheet line.
+// Lines starting with '#' and empty lines are skipped.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::path::Path;
+
+pub struct PresetLibrary {
+    // (instrument, preset name) -> parameters
+    presets: HashMap<(String, String), Vec<(String, String)>>,
+}
+
+impl Default for PresetLibrary {
+    // library with the built-in presets for every instrument that comes with wav_gen
+    fn default() -> PresetLibrary {
+        let mut library = PresetLibrary::new();
+        let pars = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+            list.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        // presets can hold notesheet settings too, the pad gets a slow and shallow vibrato
+        library.add("sinewave", "warm_pad", pars(&[("volume", "0.6"), ("vibrato", "0.1"), ("vibrato_rate", "4")]));
+        library.add("sinewave", "sub_octave", pars(&[("freq_mod", "0.5")]));
+        library.add("sinewave", "octave_up", pars(&[("freq_mod", "2"), ("volume", "0.3")]));
+        library.add("sinewave", "fifth_up", pars(&[("freq_mod", "1.5"), ("volume", "0.2")]));
+        library.add("sinewave", "quiet", pars(&[("volume", "0.3")]));
+        library.add("simpledrum", "soft_mallet", pars(&[("clickiness", "0.3"), ("volume", "0.7")]));
+        library.add("simpledrum", "hard_mallet", pars(&[("clickiness", "0.08")]));
+        library.add("simpledrum", "low_tom", pars(&[("freq_mod", "0.5"), ("clickiness", "0.25")]));
+        library
+    }
+}
+
+impl PresetLibrary {
+    // empty library
+    pub fn new() -> PresetLibrary {
+        PresetLibrary {
+            presets: HashMap::new(),
+        }
+    }
+
+    // names are case insensitive, adding an existing preset replaces it
+    pub fn add(&mut self, instrument: &str, name: &str, pars: Vec<(String, String)>) {
+        self.presets.insert(
+            (
+                instrument.trim().to_ascii_lowercase(),
+                name.trim().to_ascii_lowercase(),
+            ),
+            pars,
+        );
+    }
+
+    pub fn get(&self, instrument: &str, name: &str) -> Option<&Vec<(String, String)>> {
+        self.presets.get(&(
+            instrument.trim().to_ascii_lowercase(),
+            name.trim().to_ascii_lowercase(),
+        ))
+    }
+
+    // parameters of a notesheet line with its presets written out, a preset goes first,
+    // so that the parameters written in the line override it
+    pub fn expand(&self, instrument: &str, pars: Vec<(String, String)>) -> Result<Vec<(String, String)>, String> {
+        let mut preset_pars: Vec<(String, String)> = Vec::new();
+        let mut inline_pars: Vec<(String, String)> = Vec::new();
+        for par in pars {
+            if par.0 == "preset" {
+                match self.get(instrument, &par.1) {
+                    Some(pars) => preset_pars.extend(pars.iter().cloned()),
+                    None => return Err(format!("preset {} not found for {instrument}", par.1)),
+                }
+            } else {
+                inline_pars.push(par);
+            }
+        }
+        Ok([preset_pars, inline_pars].concat())
+    }
+
+    // reads presets from a .wavgp file, syntax errors are reported with the file name and line
+    pub fn load_file(&mut self, path: &Path) -> std::io::Result<()> {
+        let file = BufReader::new(fs::File::open(path)?);
+        let mut counter: i64 = 0;
+        for wrapped in file.lines() {
+            counter += 1;
+            let line = wrapped?.trim().to_ascii_lowercase();
+            if line.get(0..1) == Some("#") || line.is_empty() {
+                continue;
+            }
+            let mut entries = line.split(',');
+            let (name, instrument) = match entries.next().unwrap().split_once(':') {
+                Some((name, instrument)) if !name.trim().is_empty() && !instrument.trim().is_empty() => {
+                    (name.trim(), instrument.trim())
+                }
+                _ => panic!(
+                    "wavgp synthax error: expected \"name: instrument\" at line {counter} of {}",
+                    path.display()
+                ),
+            };
+            let mut pars: Vec<(String, String)> = Vec::new();
+            for entry in entries {
+                if entry.trim().is_empty() {
+                    continue;
+                }
+                match entry.split_once(':') {
+                    Some((key, value)) => pars.push((key.trim().to_string(), value.trim().to_string())),
+                    None => panic!(
+                        "wavgp synthax error: no colon in parameter definition at line {counter} of {}",
+                        path.display()
+                    ),
+                }
+            }
+            self.add(instrument, name, pars);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,187 @@
+// Scala scale (.scl) and keyboard mapping (.kbm) files, see https://www.huygens-fokker.org/scala/scl_format.html
+// Tones of the notesheet are treated as keys of a MIDI keyboard (A4 is key 69, every semitone is a key),
+// the keyboard mapping turns keys into degrees of the scale, and the scale turns degrees into cents.
+
+// global settings:
+// scale: file.scl - switches the tuning to the scale
+// keyboard_map: file.kbm - optional, without it every key is the next degree, degree 0 is on the tonic
+// of the 4th octave and A4 sounds at the a4 frequency
+
+use std::fs;
+use std::path::Path;
+
+#[derive(Clone)]
+pub struct Scale {
+    // cents of the degrees 1..N, the last one is the period (usually an octave)
+    cents: Vec<f64>,
+}
+
+#[derive(Clone)]
+pub struct KeyboardMap {
+    // keys per repetition of the mapping, 0 means every key is the next degree
+    size: usize,
+    // key that plays degree 0
+    middle: i64,
+    // key that sounds at the reference frequency
+    reference: i64,
+    frequency: f64,
+    // degree that one repetition of the mapping moves by
+    octave_degree: i64,
+    // degree of each key of the repetition, None for the keys that are not mapped
+    map: Vec<Option<i64>>,
+}
+
+// lines that are not comments, with their line numbers
+fn data_lines(text: &str) -> Vec<(usize, &str)> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.starts_with('!'))
+        .map(|(i, line)| (i + 1, line.trim()))
+        .collect()
+}
+
+fn first_word(line: &str) -> &str {
+    line.split_whitespace().next().unwrap_or("")
+}
+
+impl Scale {
+    pub fn load(path: &Path) -> std::io::Result<Scale> {
+        Ok(Scale::parse(&fs::read_to_string(path)?, &path.display().to_string()))
+    }
+
+    // file_name is only used for the error messages
+    pub fn parse(text: &str, file_name: &str) -> Scale {
+        let lines = data_lines(text);
+        // first line is the description, which can be empty
+        let (count_line, count) = match lines.get(1) {
+            Some((i, line)) => (*i, first_word(line)),
+            None => panic!("scl synthax error: no number of notes in {file_name}"),
+        };
+        let count = match count.parse::<usize>() {
+            Ok(n) => n,
+            Err(_) => panic!("scl synthax error: incorrect number of notes at line {count_line} of {file_name}"),
+        };
+        let mut cents: Vec<f64> = Vec::with_capacity(count);
+        for (i, line) in lines.iter().skip(2).take(count) {
+            let pitch = first_word(line);
+            // values with a dot are cents, the rest are ratios
+            let value = if pitch.contains('.') {
+                pitch.parse::<f64>().ok()
+            } else {
+                match pitch.split_once('/') {
+                    Some((num, den)) => num.parse::<f64>().ok().zip(den.parse::<f64>().ok()).map(|(n, d)| n / d),
+                    None => pitch.parse::<f64>().ok(),
+                }
+                .filter(|ratio| *ratio > 0.)
+                .map(|ratio| 1200. * ratio.log2())
+            };
+            match value {
+                Some(val) => cents.push(val),
+                None => panic!("scl synthax error: incorrect pitch at line {i} of {file_name}"),
+            }
+        }
+        if cents.len() != count || count == 0 {
+            panic!("scl synthax error: expected {count} notes in {file_name}, found {}", cents.len());
+        }
+        Scale { cents }
+    }
+
+    pub fn len(&self) -> usize {
+        self.cents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.cents.is_empty()
+    }
+
+    // cents of a degree above degree 0, degrees outside of the scale repeat it by periods
+    pub fn cents(&self, degree: i64) -> f64 {
+        let size = self.cents.len() as i64;
+        let period = self.cents[self.cents.len() - 1];
+        let step = degree.rem_euclid(size) as usize;
+        let base = if step == 0 { 0. } else { self.cents[step - 1] };
+        degree.div_euclid(size) as f64 * period + base
+    }
+}
+
+impl KeyboardMap {
+    // every key is the next degree of the scale
+    pub fn linear(middle: i64, reference: i64, frequency: f64) -> KeyboardMap {
+        KeyboardMap {
+            size: 0,
+            middle,
+            reference,
+            frequency,
+            octave_degree: 0,
+            map: Vec::new(),
+        }
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<KeyboardMap> {
+        Ok(KeyboardMap::parse(&fs::read_to_string(path)?, &path.display().to_string()))
+    }
+
+    // first and last retuned keys are read, but every key is retuned
+    pub fn parse(text: &str, file_name: &str) -> KeyboardMap {
+        let lines = data_lines(text);
+        let header = |n: usize| -> &str {
+            match lines.get(n) {
+                Some((_, line)) => first_word(line),
+                None => panic!("kbm synthax error: file {file_name} ends too early"),
+            }
+        };
+        let header_int = |n: usize| -> i64 {
+            match header(n).parse::<i64>() {
+                Ok(val) => val,
+                Err(_) => panic!("kbm synthax error: incorrect number at line {} of {file_name}", lines[n].0),
+            }
+        };
+        let size = header_int(0).max(0) as usize;
+        let middle = header_int(3);
+        let reference = header_int(4);
+        let frequency = match header(5).parse::<f64>() {
+            Ok(val) if val > 0. => val,
+            _ => panic!("kbm synthax error: incorrect reference frequency at line {} of {file_name}", lines[5].0),
+        };
+        let octave_degree = header_int(6);
+        let mut map: Vec<Option<i64>> = Vec::with_capacity(size);
+        for n in 7..7 + size {
+            // the mapping can be cut short, the rest of the keys are not mapped
+            map.push(match lines.get(n).map(|(_, line)| first_word(line)) {
+                None | Some("x") | Some("X") => None,
+                Some(degree) => match degree.parse::<i64>() {
+                    Ok(val) => Some(val),
+                    Err(_) => panic!("kbm synthax error: incorrect degree at line {} of {file_name}", lines[n].0),
+                },
+            });
+        }
+        KeyboardMap {
+            size,
+            middle,
+            reference,
+            frequency,
+            octave_degree,
+            map,
+        }
+    }
+
+    pub fn degree(&self, key: i64) -> Option<i64> {
+        let offset = key - self.middle;
+        if self.size == 0 {
+            return Some(offset);
+        }
+        let repetition = offset.div_euclid(self.size as i64);
+        self.map[offset.rem_euclid(self.size as i64) as usize]
+            .map(|degree| repetition * self.octave_degree + degree)
+    }
+
+    // frequency of a key, keys that are not mapped have none
+    pub fn frequency(&self, scale: &Scale, key: i64) -> Option<f64> {
+        let reference = match self.degree(self.reference) {
+            Some(degree) => scale.cents(degree),
+            None => panic!("kbm error: reference key {} is not mapped", self.reference),
+        };
+        self.degree(key)
+            .map(|degree| self.frequency * ((scale.cents(degree) - reference) / 1200.).exp2())
+    }
+}
@@ -0,0 +1,106 @@
+// Source reads a .wavg file together with the files it includes, so that parts of a song can live in separate files.
+// "Include: drums.wavg" is replaced by the lines of drums.wavg, which is looked up relative to the including file.
+// Included files are read until their "end" line, so a part can also be played on its own.
+// Every line remembers the file it comes from, for the error messages and for the paths of that file.
+
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// place of a line, shown as "12 of drums.wavg"
+#[derive(Clone, Copy)]
+pub struct Line<'a> {
+    pub file: &'a Path,
+    pub number: i64,
+}
+
+impl Line<'_> {
+    // directory that the paths written on this line are relative to
+    pub fn dir(&self) -> &Path {
+        self.file.parent().unwrap_or(Path::new(""))
+    }
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} of {}", self.number, self.file.display())
+    }
+}
+
+pub struct Source {
+    files: Vec<PathBuf>,
+    // (index of the file, line number, text)
+    lines: Vec<(usize, i64, String)>,
+}
+
+// file name of an "Include: file.wavg" line, which is case sensitive
+fn included(raw: &str) -> Option<&str> {
+    let (key, value) = raw.split_once(':')?;
+    if key.trim().eq_ignore_ascii_case("include") {
+        Some(value.trim())
+    } else {
+        None
+    }
+}
+
+impl Source {
+    pub fn read(path: &Path) -> std::io::Result<Source> {
+        let mut source = Source {
+            files: Vec::new(),
+            lines: Vec::new(),
+        };
+        let text = fs::read_to_string(path)?;
+        source.add_file(path, &text, &mut Vec::new());
+        Ok(source)
+    }
+
+    // stack holds the files that are being included, from the main one
+    fn add_file(&mut self, path: &Path, text: &str, stack: &mut Vec<PathBuf>) {
+        let index = self.files.len();
+        self.files.push(path.to_path_buf());
+        // the same file can be written in different ways
+        stack.push(fs::canonicalize(path).unwrap_or(path.to_path_buf()));
+        for (i, raw) in text.lines().enumerate() {
+            let number = i as i64 + 1;
+            let Some(name) = included(raw) else {
+                // only the main file ends the song
+                if stack.len() > 1 && raw.trim().eq_ignore_ascii_case("end") {
+                    break;
+                }
+                self.lines.push((index, number, raw.to_string()));
+                continue;
+            };
+            let line = Line {
+                file: path,
+                number,
+            };
+            let include = line.dir().join(name);
+            let canonical = fs::canonicalize(&include).unwrap_or(include.clone());
+            if let Some(start) = stack.iter().position(|file| *file == canonical) {
+                let cycle: Vec<String> = stack[start..]
+                    .iter()
+                    .chain([&canonical])
+                    .map(|file| file.display().to_string())
+                    .collect();
+                panic!("wavg synthax error: include cycle {} at line {line}", cycle.join(" -> "));
+            }
+            match fs::read_to_string(&include) {
+                Ok(text) => self.add_file(&include, &text, stack),
+                Err(e) => panic!("wavg synthax error: can't include {}: {e} at line {line}", include.display()),
+            }
+        }
+        stack.pop();
+    }
+
+    pub fn lines(&self) -> impl Iterator<Item = (Line<'_>, &str)> {
+        self.lines.iter().map(|(file, number, raw)| {
+            (
+                Line {
+                    file: &self.files[*file],
+                    number: *number,
+                },
+                raw.as_str(),
+            )
+        })
+    }
+}
@@ -0,0 +1,190 @@
+// Tempo map keeps the tempo and time signature changes of the song.
+// Positions in the song are measured in 1/4th since its beginning, and are turned into seconds
+// by integrating over the tempo, so that gradual changes (accelerando, ritardando) place the notes correctly.
+
+// changes are declared with:
+// At bar 17: bpm 140, time_signature 3/4
+// Accelerando: 9 120 -> 12 160 (from the beginning of bar 9 to the end of bar 12, ritardando is the same)
+
+struct TempoChange {
+    // bar at the beginning of which the change happens
+    bar: i64,
+    bpm: f64,
+    // gradual changes end at the end of this bar with this bpm
+    ramp: Option<(i64, f64)>,
+}
+
+pub struct TempoMap {
+    // (bar, time signature) sorted by bar, the first one is at bar 1
+    signatures: Vec<(i64, (i64, i64))>,
+    // sorted by bar, the first one is at bar 1
+    tempos: Vec<TempoChange>,
+}
+
+impl Default for TempoMap {
+    fn default() -> TempoMap {
+        TempoMap::new(120., (4, 4))
+    }
+}
+
+impl TempoMap {
+    pub fn new(bpm: f64, time_signature: (i64, i64)) -> TempoMap {
+        TempoMap {
+            signatures: vec![(1, time_signature)],
+            tempos: vec![TempoChange {
+                bar: 1,
+                bpm,
+                ramp: None,
+            }],
+        }
+    }
+
+    // tempo of the beginning of the song
+    pub fn set_initial_bpm(&mut self, bpm: f64) {
+        self.tempos[0].bpm = bpm;
+    }
+    pub fn set_initial_time_signature(&mut self, time_signature: (i64, i64)) {
+        self.signatures[0].1 = time_signature;
+    }
+
+    // a change at a bar replaces the previous change at the same bar
+    pub fn set_bpm(&mut self, bar: i64, bpm: f64) {
+        self.add_tempo(TempoChange {
+            bar,
+            bpm,
+            ramp: None,
+        });
+    }
+    pub fn set_time_signature(&mut self, bar: i64, time_signature: (i64, i64)) {
+        self.signatures.retain(|(b, _)| *b != bar);
+        self.signatures.push((bar, time_signature));
+        self.signatures.sort_by_key(|(b, _)| *b);
+    }
+    // tempo goes from bpm at the beginning of start_bar to end_bpm at the end of end_bar
+    pub fn add_ramp(&mut self, start_bar: i64, bpm: f64, end_bar: i64, end_bpm: f64) {
+        self.add_tempo(TempoChange {
+            bar: start_bar,
+            bpm,
+            ramp: Some((end_bar, end_bpm)),
+        });
+    }
+    fn add_tempo(&mut self, change: TempoChange) {
+        self.tempos.retain(|t| t.bar != change.bar);
+        self.tempos.push(change);
+        self.tempos.sort_by_key(|t| t.bar);
+    }
+
+    pub fn time_signature(&self, bar: i64) -> (i64, i64) {
+        let mut signature = self.signatures[0].1;
+        for (b, s) in &self.signatures {
+            if *b <= bar {
+                signature = *s;
+            }
+        }
+        signature
+    }
+
+    // length of a bar in 1/4th
+    pub fn bar_length(&self, bar: i64) -> f64 {
+        let (num, den) = self.time_signature(bar);
+        4. * num as f64 / den as f64
+    }
+
+    // 1/4th since the beginning of the song until the beginning of the bar
+    pub fn bar_position(&self, bar: i64) -> f64 {
+        let mut position = 0.;
+        for (i, (b, signature)) in self.signatures.iter().enumerate() {
+            let next = match self.signatures.get(i + 1) {
+                Some((next, _)) => (*next).min(bar),
+                None => bar,
+            };
+            if next > *b {
+                position += (next - b) as f64 * 4. * signature.0 as f64 / signature.1 as f64;
+            }
+        }
+        position
+    }
+
+    // tempo segments: (start, end, bpm at start, bpm at end), positions in 1/4th, the last one never ends
+    fn segments(&self) -> Vec<(f64, f64, f64, f64)> {
+        let mut segments: Vec<(f64, f64, f64, f64)> = Vec::new();
+        for (i, change) in self.tempos.iter().enumerate() {
+            let start = self.bar_position(change.bar);
+            let next = match self.tempos.get(i + 1) {
+                Some(next) => self.bar_position(next.bar),
+                None => f64::INFINITY,
+            };
+            match change.ramp {
+                Some((end_bar, end_bpm)) => {
+                    let end = self.bar_position(end_bar + 1).min(next);
+                    // ramp is cut short if another change comes before its end
+                    let reached = crate::math::linerp(
+                        (start, change.bpm),
+                        (self.bar_position(end_bar + 1), end_bpm),
+                        end,
+                    );
+                    segments.push((start, end, change.bpm, reached));
+                    if next > end {
+                        segments.push((end, next, reached, reached));
+                    }
+                }
+                None => segments.push((start, next, change.bpm, change.bpm)),
+            }
+        }
+        segments
+    }
+
+    pub fn bpm_at(&self, position: f64) -> f64 {
+        for (start, end, from, to) in self.segments() {
+            if position >= start && position < end {
+                if from == to {
+                    return from;
+                }
+                return crate::math::linerp((start, from), (end, to), position);
+            }
+        }
+        self.tempos[0].bpm
+    }
+
+    // seconds since the beginning of the song until the position
+    pub fn seconds(&self, position: f64) -> f64 {
+        let mut seconds = 0.;
+        for (start, end, from, to) in self.segments() {
+            if position <= start {
+                break;
+            }
+            let until = position.min(end);
+            let to_at_until = if from == to {
+                from
+            } else {
+                crate::math::linerp((start, from), (end, to), until)
+            };
+            seconds += if (to_at_until - from).abs() < 1e-12 {
+                (until - start) * 60. / from
+            } else {
+                // 60 / bpm integrated over a linear change of bpm
+                let slope = (to_at_until - from) / (until - start);
+                60. / slope * (to_at_until / from).ln()
+            };
+        }
+        seconds
+    }
+
+    // position in 1/4th at this many seconds since the beginning of the song, the inverse of seconds()
+    pub fn position(&self, seconds: f64) -> f64 {
+        let mut high = 4.;
+        while self.seconds(high) < seconds {
+            high *= 2.;
+        }
+        let mut low = 0.;
+        for _ in 0..60 {
+            let middle = (low + high) / 2.;
+            if self.seconds(middle) < seconds {
+                low = middle;
+            } else {
+                high = middle;
+            }
+        }
+        (low + high) / 2.
+    }
+}
@@ -1 +1,917 @@
+use crate::arpeggio::Arpeggio;
+use crate::arrange::{arrange, parse_arrangement, Section};
+use crate::automation::Automation;
+use crate::chords::{is_chord_symbol, ChordSymbol, Voicing, VoicingStyle};
+use crate::effects::{parse_effect, Effect};
+use crate::groove::Groove;
+use crate::harmonics::{merge_ties, parse_bar, Dynamics, NoteContext};
+use crate::humanize::Humanize;
+use crate::instruments::{Instrument, Note, Registry};
+use crate::keys::KeySignature;
+use crate::legato::Legato;
+use crate::math::Rng;
+use crate::mixer::{amplitude, Mixer};
+use crate::modulation::{is_source, Modulation};
+use crate::presets::PresetLibrary;
+use crate::scala::{KeyboardMap, Scale};
+use crate::source::{Line, Source};
+use crate::tempo::TempoMap;
+use crate::track::Track;
+use crate::transform::Transform;
+use crate::tuning::Tuning;
+use crate::variables::{evaluate, Variables};
+use crate::vibrato::Vibrato;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
+// notes of a bar of a sequential notesheet with the default settings
+fn bar(s: &str) -> Vec<Note> {
+    tuned_bar(s, &Tuning::default(), &Transform::default(), &KeySignature::default())
+}
+
+fn tuned_bar(s: &str, tuning: &Tuning, transform: &Transform, key: &KeySignature) -> Vec<Note> {
+    parse(s, tuning, transform, key).unwrap()
+}
+
+// error of a bar with the default settings
+fn bar_error(s: &str) -> String {
+    parse(s, &Tuning::default(), &Transform::default(), &KeySignature::default()).err().unwrap()
+}
+
+fn parse(s: &str, tuning: &Tuning, transform: &Transform, key: &KeySignature) -> Result<Vec<Note>, String> {
+    let ctx = NoteContext {
+        tempo: &TempoMap::default(),
+        bar_timing: 0.,
+        dynamics: &Dynamics::new(1.),
+        sequential: true,
+        voicing: &Voicing::default(),
+        tuning,
+        key,
+        groove: &Groove::default(),
+        arp: &Arpeggio::default(),
+        vibrato: &Vibrato::default(),
+        transform,
+    };
+    parse_bar(s, &ctx)
+}
+
+fn freqs(notes: &[Note]) -> Vec<f64> {
+    notes.iter().map(|note| (note.freq() * 100.).round() / 100.).collect()
+}
+
+#[test]
+fn uppercase_tones_are_notes() {
+    assert_eq!(freqs(&bar("C4 1/4")), freqs(&bar("c4 1/4")));
+    assert_eq!(freqs(&bar("G7 1/4")), vec![3135.96]);
+    assert_eq!(freqs(&bar("A-1 1/4")), vec![13.75]);
+    // there is nothing to play above octave 9 or below octave -1
+    assert!(bar_error("G13 1/4").contains("out of range"));
+    assert!(bar_error("C-7 1/4").contains("out of range"));
+}
+
+#[test]
+fn chord_symbols_are_chords() {
+    assert_eq!(freqs(&bar("Gdom7 1/4")), freqs(&bar("[g4 b4 d5 f5] 1/4")));
+    assert_eq!(freqs(&bar("G7b9 1/4")).len(), 5);
+    // in quotes, a number only is the quality of the chord
+    assert_eq!(freqs(&bar("\"G7\" 1/4")), freqs(&bar("Gdom7 1/4")));
+    assert_eq!(freqs(&bar("\"C5\" 1/4")), freqs(&bar("[c4 g4] 1/4")));
+    assert_eq!(freqs(&bar("\"A13\" 1/4")).len(), 6);
+    assert!(bar_error("\"Cmaj7\" sharp 1/4").contains("can't have variants"));
+}
+
+#[test]
+fn chord_symbols_are_told_apart_from_tones() {
+    assert!(is_chord_symbol("C") && is_chord_symbol("Cmaj7") && is_chord_symbol("F#m7b5") && is_chord_symbol("Bb7"));
+    assert!(!is_chord_symbol("C4") && !is_chord_symbol("B-1") && !is_chord_symbol("c") && !is_chord_symbol("r"));
+    assert!(is_chord_symbol("\"C4\"") && !is_chord_symbol("\""));
+}
+
+#[test]
+fn chord_symbols_are_voiced() {
+    let close = Voicing::default();
+    // semitones from A4, C4 is 9 below it
+    assert_eq!(ChordSymbol::parse("C").unwrap().voice(&close), vec![-9, -5, -2]);
+    assert_eq!(ChordSymbol::parse("Cm7").unwrap().voice(&close), vec![-9, -6, -2, 1]);
+    assert_eq!(ChordSymbol::parse("Csus4").unwrap().voice(&close), vec![-9, -4, -2]);
+    assert_eq!(ChordSymbol::parse("C7b9").unwrap().voice(&close), vec![-9, -5, -2, 1, 4]);
+    assert_eq!(ChordSymbol::parse("Gdom7").unwrap().voice(&close), ChordSymbol::parse("G7b9").unwrap().voice(&close)[..4].to_vec());
+    // bass right under the chord
+    assert_eq!(ChordSymbol::parse("C/E").unwrap().voice(&close), vec![-17, -9, -5, -2]);
+    let first = Voicing {
+        inversion: 1,
+        ..Voicing::default()
+    };
+    assert_eq!(ChordSymbol::parse("C").unwrap().voice(&first), vec![-5, -2, 3]);
+    let open = Voicing {
+        style: VoicingStyle::Open,
+        ..Voicing::default()
+    };
+    assert_eq!(ChordSymbol::parse("C").unwrap().voice(&open), vec![-9, -2, 7]);
+}
+
+#[test]
+fn notes_are_parsed() {
+    // a sharp and a numeric variant are the same tone, rests are silent
+    assert_eq!(freqs(&bar("c4 sharp 1/4")), freqs(&bar("c4 1 1/4")));
+    assert_eq!(freqs(&bar("a4 1/4, r 1/4, a5 1/4")), vec![440., 880.]);
+    assert_eq!(freqs(&bar("a4 +50c 1/4")), freqs(&bar("a4 half-sharp 1/4")));
+    assert_eq!(freqs(&bar("440hz 1/4")), vec![440.]);
+    // sequential notes follow one another, a quarter note is half a second at 120 bpm
+    let notes = bar("a4 1/4, a4 1/2, a4 1/4 v64");
+    let times: Vec<f64> = notes.iter().map(|note| note.time()).collect();
+    assert_eq!(times, vec![0., 0.5, 1.5]);
+    assert_eq!(notes[1].leng(), 1.);
+    assert!(notes[2].loud() < notes[0].loud());
+}
+
+// start and length of the notes in seconds, rounded to the microsecond
+fn timing(notes: &[Note]) -> Vec<(f64, f64)> {
+    let round = |t: f64| (t * 1e6).round() / 1e6;
+    notes.iter().map(|note| (round(note.time()), round(note.leng()))).collect()
+}
+
+#[test]
+fn tuplets_share_their_span() {
+    // 3 eighths in the time of 2 at 120 bpm, a sixth of a second each
+    let triplet = bar("3:2{c4 1/8, d4 1/8, e4 1/8}, f4 1/4");
+    assert_eq!(timing(&triplet), vec![(0., 0.166667), (0.166667, 0.166667), (0.333333, 0.166667), (0.5, 0.5)]);
+    let quintuplet = bar("5:4{c4 1/16, d4 1/16, e4 1/16, f4 1/16, g4 1/16}, a4 1/4");
+    assert_eq!(timing(&quintuplet), vec![(0., 0.1), (0.1, 0.1), (0.2, 0.1), (0.3, 0.1), (0.4, 0.1), (0.5, 0.5)]);
+    // the last note of the triplet is held into the quarter after it
+    let tied = merge_ties(bar("3:2{c4 1/8, d4 1/8, e4 1/8 ~}, e4 1/4")).unwrap();
+    assert_eq!(timing(&tied), vec![(0., 0.166667), (0.166667, 0.166667), (0.333333, 0.666667)]);
+}
+
+#[test]
+fn grids_keep_their_velocity() {
+    let loud: Vec<f64> = bar("c3 x...x...").iter().map(|note| note.loud()).collect();
+    let soft: Vec<f64> = bar("c3 v64 x...X...").iter().map(|note| note.loud()).collect();
+    assert_eq!(loud.len(), 2);
+    assert!(soft[0] < loud[0]);
+    assert_eq!(soft[1], loud[1]);
+}
+
+#[test]
+fn grids_have_their_own_step() {
+    let grid = |settings: &[(&str, &str)]| {
+        let mut arp = Arpeggio::default();
+        for (key, value) in settings {
+            arp.update(&setting(key, value)).unwrap();
+        }
+        let ctx = NoteContext {
+            tempo: &TempoMap::default(),
+            bar_timing: 0.,
+            dynamics: &Dynamics::new(1.),
+            sequential: true,
+            voicing: &Voicing::default(),
+            tuning: &Tuning::default(),
+            key: &KeySignature::default(),
+            groove: &Groove::default(),
+            arp: &arp,
+            vibrato: &Vibrato::default(),
+            transform: &Transform::default(),
+        };
+        timing(&parse_bar("c3 x.x-", &ctx).unwrap())
+    };
+    assert_eq!(grid(&[]), vec![(0., 0.125), (0.25, 0.25)]);
+    // the arpeggio rate doesn't change the grids
+    assert_eq!(grid(&[("arp", "up"), ("arp_rate", "1/8")]), vec![(0., 0.125), (0.25, 0.25)]);
+    assert_eq!(grid(&[("grid_step", "1/8")]), vec![(0., 0.25), (0.5, 0.5)]);
+}
+
+#[test]
+fn ties_need_a_continuation() {
+    assert_eq!(merge_ties(bar("c4 1/4 ~, c4 1/4")).unwrap().len(), 1);
+    assert!(merge_ties(bar("c4 1/4 ~, d4 1/4")).is_err());
+}
+
+#[test]
+fn crescendos_go_through_every_velocity_on_the_way() {
+    let mut dynamics = Dynamics::new(0.5);
+    // from the beginning of the bar to its end, and the level of its end after it
+    dynamics.add_hairpin(0., 4., 0.2, 0.6);
+    let ctx = NoteContext {
+        tempo: &TempoMap::default(),
+        bar_timing: 0.,
+        dynamics: &dynamics,
+        sequential: true,
+        voicing: &Voicing::default(),
+        tuning: &Tuning::default(),
+        key: &KeySignature::default(),
+        groove: &Groove::default(),
+        arp: &Arpeggio::default(),
+        vibrato: &Vibrato::default(),
+        transform: &Transform::default(),
+    };
+    let notes = parse_bar("c4 1/4, d4 1/4, e4 1/4 v127, f4 1/4, g4 1/4", &ctx).unwrap();
+    let velocities: Vec<f64> = notes.iter().map(|note| (note.loud() * 1e9).round() / 1e9).collect();
+    // a written velocity is kept
+    assert_eq!(velocities, vec![0.2, 0.3, 1., 0.5, 0.6]);
+    dynamics.set(0.4);
+    assert_eq!(dynamics.velocity_at(2.), 0.4);
+}
+
+fn grooved_bar(s: &str, groove: &Groove) -> Vec<Note> {
+    let ctx = NoteContext {
+        tempo: &TempoMap::default(),
+        bar_timing: 0.,
+        dynamics: &Dynamics::new(1.),
+        sequential: true,
+        voicing: &Voicing::default(),
+        tuning: &Tuning::default(),
+        key: &KeySignature::default(),
+        groove,
+        arp: &Arpeggio::default(),
+        vibrato: &Vibrato::default(),
+        transform: &Transform::default(),
+    };
+    parse_bar(s, &ctx).unwrap()
+}
+
+#[test]
+fn swing_moves_every_second_step() {
+    let mut groove = Groove::default();
+    groove.update(&setting("swing", "75")).unwrap();
+    // the second eighth starts on the last sixteenth, the first one is held until then
+    let notes = grooved_bar("c4 1/8, d4 1/8, e4 1/8, f4 1/8", &groove);
+    assert_eq!(timing(&notes), vec![(0., 0.375), (0.375, 0.125), (0.5, 0.375), (0.875, 0.125)]);
+    assert!(notes.iter().all(|note| note.loud() == 1.));
+    // a note tied over the swung step keeps sounding until the one it is tied to ends
+    let tied = merge_ties(grooved_bar("c4 1/8, d4 1/8 ~, d4 1/8, e4 1/8", &groove)).unwrap();
+    assert_eq!(timing(&tied), vec![(0., 0.375), (0.375, 0.5), (0.875, 0.125)]);
+}
+
+#[test]
+fn groove_offsets_and_velocities_repeat() {
+    let mut groove = Groove::default();
+    groove.update(&setting("groove_grid", "1/16")).unwrap();
+    groove.update(&setting("groove_offsets", "0 0.5")).unwrap();
+    groove.update(&setting("groove_velocities", "1 0.5")).unwrap();
+    let notes = grooved_bar("c4 1/16, d4 1/16, e4 1/16, f4 1/16", &groove);
+    let starts: Vec<f64> = timing(&notes).iter().map(|(start, _)| *start).collect();
+    assert_eq!(starts, vec![0., 0.1875, 0.25, 0.4375]);
+    let velocities: Vec<f64> = notes.iter().map(|note| note.loud()).collect();
+    assert_eq!(velocities, vec![1., 0.5, 1., 0.5]);
+    assert!(groove.update(&setting("groove_offsets", "")).is_err());
+}
+
+fn humanized(seed: &str) -> Vec<Note> {
+    let mut humanize = Humanize::default();
+    humanize.update(&setting("humanize", "1")).unwrap();
+    humanize.update(&setting("humanize_seed", seed)).unwrap();
+    humanize.apply(bar("a4 1/4 v64, b4 1/4 v64, c5 1/4 v64, d5 1/4 v64"), 0)
+}
+
+#[test]
+fn humanize_is_seeded() {
+    let once = humanized("7");
+    let twice = humanized("7");
+    assert_eq!(timing(&once), timing(&twice));
+    assert_eq!(freqs(&once), freqs(&twice));
+    let other = humanized("8");
+    assert_ne!(timing(&once), timing(&other));
+}
+
+#[test]
+fn humanize_stays_within_its_amounts() {
+    let straight = bar("a4 1/4 v64, b4 1/4 v64, c5 1/4 v64, d5 1/4 v64");
+    for seed in 0..20 {
+        for (note, played) in straight.iter().zip(humanized(&seed.to_string())) {
+            // humanize 1 is 10 ms, 5% of the length, 0.05 velocity and 5 cents
+            assert!((played.time() - note.time()).abs() <= 0.01);
+            assert!((played.leng() / note.leng() - 1.).abs() <= 0.05);
+            assert!((played.loud() - note.loud()).abs() <= 0.05);
+            assert!((1200. * (played.freq() / note.freq()).log2()).abs() <= 5.);
+        }
+    }
+    let mut rng = Rng::new(1);
+    assert!((0..1000).map(|_| rng.symmetric()).all(|x| (-1. ..1.).contains(&x)));
+}
+
+fn setting(key: &str, value: &str) -> (String, String) {
+    (key.to_string(), value.to_string())
+}
+
+#[test]
+fn a4_is_the_reference() {
+    let mut tuning = Tuning::default();
+    assert_eq!(tuning.frequency(0.), 440.);
+    tuning.update(&setting("a4", "432")).unwrap();
+    assert_eq!(tuning.frequency(0.), 432.);
+    assert_eq!(tuning.frequency(12.), 864.);
+    assert!((tuning.frequency(-9.) - 432. * (-9_f64 / 12.).exp2()).abs() < 1e-9);
+    assert!(tuning.update(&setting("a4", "-440")).is_err());
+}
+
+#[test]
+fn just_intervals_are_pure_from_the_tonic() {
+    let equal = Tuning::default();
+    let mut just = Tuning::default();
+    just.update(&setting("tuning", "just")).unwrap();
+    // the tonic is tuned as in 12tet, the other tones are ratios of it
+    let c4 = equal.frequency(-9.);
+    assert!((just.frequency(-9.) - c4).abs() < 1e-9);
+    assert!((just.frequency(-5.) - c4 * 5. / 4.).abs() < 1e-9);
+    assert!((just.frequency(-2.) - c4 * 3. / 2.).abs() < 1e-9);
+    assert!((just.frequency(6.) - c4 * 2. * 6. / 5.).abs() < 1e-9);
+    // a just major third is lower than an equal one
+    assert!(just.frequency(-5.) < equal.frequency(-5.));
+    just.update(&setting("tonic", "a")).unwrap();
+    assert!((just.frequency(0.) - 440.).abs() < 1e-9);
+    assert!((just.frequency(4.) - 440. * 5. / 4.).abs() < 1e-9);
+}
+
+#[test]
+fn transforms_move_tones_before_tuning() {
+    let mut just = Tuning::default();
+    just.update(&setting("tuning", "just")).unwrap();
+    let mut transform = Transform::default();
+    transform.update(&setting("transpose", "4")).unwrap();
+    assert_eq!(freqs(&tuned_bar("c4 1/4", &just, &transform, &KeySignature::default())), freqs(&tuned_bar("e4 1/4", &just, &Transform::default(), &KeySignature::default())));
+    let mut transform = Transform::default();
+    transform.update(&setting("invert", "e4")).unwrap();
+    assert_eq!(freqs(&tuned_bar("c4 1/4", &just, &transform, &KeySignature::default())), freqs(&tuned_bar("g4 sharp 1/4", &just, &Transform::default(), &KeySignature::default())));
+}
+
+#[test]
+fn time_scale_follows_the_tempo_map() {
+    let mut tempo = TempoMap::new(60., (4, 4));
+    tempo.set_bpm(2, 120.);
+    let mut transform = Transform::default();
+    transform.update(&setting("time_scale", "2")).unwrap();
+    // a whole note of bar 1 becomes two, the second one is played twice as fast in bar 2
+    let notes = transform.apply(vec![Note::new(440., 4., 0.)], &tempo);
+    assert!((notes[0].leng() - 6.).abs() < 1e-9);
+}
+
+#[test]
+fn legato_slides_between_overlapping_notes() {
+    let mut legato = Legato::default();
+    legato.update(&setting("legato", "on")).unwrap();
+    legato.update(&setting("portamento", "0.2")).unwrap();
+    let notes = legato.apply(vec![Note::new(440., 1., 0.5), Note::new(220., 1., 0.), Note::new(330., 0.5, 2.)]);
+    // the third note starts after the phrase ends
+    assert_eq!(notes.len(), 2);
+    let phrase = &notes[0];
+    assert_eq!(phrase.leng(), 1.5);
+    for (t, freq) in [(0., 220.), (0.5, 220.), (0.6, 220. * 2_f64.sqrt()), (0.7, 440.), (1.4, 440.)] {
+        assert!((phrase.freq_at(t) - freq).abs() < 1e-9);
+    }
+    assert_eq!(notes[1].freq(), 330.);
+}
+
+#[test]
+fn bends_go_through_their_points() {
+    let quarter = TempoMap::default().seconds(1.);
+    let semitones = |note: &Note, t: f64| 12. * (note.freq_at(t) / note.freq()).log2();
+    let notes = bar("g4 1/2 bend:+2@1/4");
+    for (t, expected) in [(0., 0.), (quarter / 2., 1.), (quarter, 2.), (1.5 * quarter, 2.)] {
+        assert!((semitones(&notes[0], t) - expected).abs() < 1e-9);
+    }
+    // without a time the bend ends with the note
+    let notes = bar("g4 1/2 bend:-1");
+    assert!((semitones(&notes[0], quarter) + 0.5).abs() < 1e-9);
+    assert!((semitones(&notes[0], 2. * quarter) + 1.).abs() < 1e-9);
+}
+
+#[test]
+fn retrograde_reverses_bends() {
+    let quarter = TempoMap::default().seconds(1.);
+    let mut transform = Transform::default();
+    transform.update(&setting("retrograde", "true")).unwrap();
+    let notes = transform.apply(bar("g4 1/2 bend:+2@1/4"), &TempoMap::default());
+    let g4 = bar("g4 1/4")[0].freq();
+    let semitones = |t: f64| 12. * (notes[0].freq_at(t) / g4).log2();
+    for (t, expected) in [(0., 2.), (quarter, 2.), (1.5 * quarter, 1.), (2. * quarter, 0.)] {
+        assert!((semitones(t) - expected).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn modulation_sources_are_numbered() {
+    assert!(is_source("lfo1") && is_source("env12"));
+    assert!(!is_source("lfo") && !is_source("envelope") && !is_source("lfo_rate") && !is_source("env2b"));
+}
+
+#[test]
+fn master_lanes_fill_in_for_the_notesheet() {
+    let mut master = Automation::default();
+    master.update(&setting("automate", "freq_mod bar 1 1 -> bar 2 2")).unwrap();
+    master.update(&setting("automate", "clickiness bar 1 0 -> bar 2 1")).unwrap();
+    let mut sheet = Automation::default();
+    assert!(sheet.with_master(&master, |parameter| parameter == "freq_mod").instrument_lanes().count() == 1);
+    sheet.update(&setting("automate", "freq_mod bar 1 2 -> bar 2 1")).unwrap();
+    let lanes = sheet.with_master(&master, |_| true);
+    let values: Vec<f64> = lanes.instrument_lanes().map(|lane| lane.value(0., &TempoMap::default())).collect();
+    assert_eq!(values, vec![2., 0.]);
+}
+
+#[test]
+fn automation_curve_is_a_keyword() {
+    let mut automation = Automation::default();
+    automation.update(&setting("automate", "curvature bar 1 0 -> bar 3 1 curve smooth")).unwrap();
+    assert!(automation.update(&setting("automate", "volume bar 1 0 -> bar 3 1curve exp")).is_err());
+    let lane = automation.instrument_lanes().next().unwrap();
+    assert_eq!(lane.parameter, "curvature");
+    // halfway through, smooth is where linear would be
+    assert!((lane.value(TempoMap::default().seconds(4.), &TempoMap::default()) - 0.5).abs() < 1e-9);
+}
+
+#[test]
+fn silence_is_not_normalized() {
+    let mut track = Track::new();
+    track.track = vec![0.; 100];
+    track.normalize(track.peak());
+    track.apply_loudness();
+    assert!(track.track.iter().all(|sample| *sample == 0.));
+}
+
+fn line() -> Line<'static> {
+    Line {
+        file: Path::new("test.wavg"),
+        number: 1,
+    }
+}
+
+#[test]
+fn arithmetic_keeps_fractions() {
+    assert_eq!(evaluate("1/4 * 3").as_deref(), Some("3/4"));
+    assert_eq!(evaluate("1/8 + 1/8").as_deref(), Some("1/4"));
+    assert_eq!(evaluate("(2 + 1) * -2").as_deref(), Some("-6"));
+    assert_eq!(evaluate("0.5 * 3").as_deref(), Some("1.5"));
+    assert_eq!(evaluate("1 / 0"), None);
+    assert_eq!(evaluate("c4"), None);
+}
+
+#[test]
+fn variables_are_expanded() {
+    let mut variables = Variables::default();
+    assert!(variables.define("let root = c3", line()));
+    assert!(variables.define("const q = 1/8 * 2", line()));
+    assert!(variables.define("let Base = 0.4", line()));
+    assert!(!variables.define("Notesheet: SineWave", line()));
+    assert_eq!(variables.expand("1, $root 0 $q, e3 ($q * 3) $q", line()), "1, c3 0 1/4, e3 3/4 1/4");
+    assert_eq!(variables.expand("Notesheet: SineWave, volume: $base * 2", line()), "Notesheet: SineWave, volume: 0.8");
+    // fractions of settings stay as they are written
+    assert_eq!(variables.expand("Time_Signature: 6/8", line()), "Time_Signature: 6/8");
+}
+
+#[test]
+#[should_panic(expected = "is a constant")]
+fn constants_are_not_changed() {
+    let mut variables = Variables::default();
+    variables.define("const q = 1/4", line());
+    variables.define("let q = 1/8", line());
+}
+
+#[test]
+fn scala_scales_are_parsed() {
+    let scl = "! pentatonic.scl\n!\nJust pentatonic\n 5\n!\n 9/8\n 5/4\n 701.955 fifth\n 5/3\n 2\n";
+    let scale = Scale::parse(scl, "pentatonic.scl");
+    assert_eq!(scale.len(), 5);
+    let cents: Vec<f64> = (0..7).map(|degree| (scale.cents(degree) * 1000.).round() / 1000.).collect();
+    assert_eq!(cents, vec![0., 203.91, 386.314, 701.955, 884.359, 1200., 1403.91]);
+    assert_eq!((scale.cents(-1) * 1000.).round() / 1000., -315.641);
+}
+
+#[test]
+#[should_panic(expected = "expected 3 notes")]
+fn scala_scales_have_all_their_notes() {
+    Scale::parse("short\n3\n100.\n200.\n", "short.scl");
+}
+
+#[test]
+fn keyboard_maps_skip_unmapped_keys() {
+    // 12 keys, middle C, A4 at 440 Hz, the black keys are not mapped
+    let kbm = "! white.kbm\n12\n0\n127\n60\n69\n440.0\n7\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n";
+    let keyboard = KeyboardMap::parse(kbm, "white.kbm");
+    assert_eq!(keyboard.degree(60), Some(0));
+    assert_eq!(keyboard.degree(61), None);
+    assert_eq!(keyboard.degree(72), Some(7));
+    let scale = Scale::parse("7-EDO\n7\n171.429\n342.857\n514.286\n685.714\n857.143\n1028.571\n1200.0\n", "7edo.scl");
+    assert_eq!(keyboard.frequency(&scale, 69).map(|freq| freq.round()), Some(440.));
+    assert_eq!(keyboard.frequency(&scale, 81).map(|freq| freq.round()), Some(880.));
+}
+
+#[test]
+fn tempo_maps_place_bars_and_seconds() {
+    let mut tempo = TempoMap::new(60., (4, 4));
+    tempo.set_time_signature(3, (3, 4));
+    tempo.set_bpm(5, 120.);
+    assert_eq!(tempo.bar_position(3), 8.);
+    assert_eq!(tempo.bar_position(5), 14.);
+    assert_eq!(tempo.bar_length(4), 3.);
+    // 14 quarters at 60 bpm, then 2 at 120 bpm
+    assert!((tempo.seconds(16.) - 15.).abs() < 1e-9);
+    assert!((tempo.position(15.) - 16.).abs() < 1e-6);
+}
+
+#[test]
+fn tempo_ramps_are_gradual() {
+    let mut tempo = TempoMap::new(60., (4, 4));
+    // from 60 at the beginning of bar 2 to 120 at the end of bar 2
+    tempo.add_ramp(2, 60., 2, 120.);
+    assert_eq!(tempo.bpm_at(6.), 90.);
+    assert_eq!(tempo.bpm_at(9.), 120.);
+    // 60 / bpm integrated over the ramp: 4 quarters in 4 ln 2 seconds
+    assert!((tempo.seconds(8.) - 4. - 4. * 2_f64.ln()).abs() < 1e-9);
+}
+
+#[test]
+fn key_signatures_have_their_accidentals() {
+    // C, D, E, F, G, A, B
+    let shifts = |key: &str| -> Vec<f64> {
+        let key = KeySignature::parse(key).unwrap();
+        (0..7).map(|name| key.accidental(name)).collect()
+    };
+    assert_eq!(shifts("c"), vec![0.; 7]);
+    assert_eq!(shifts("g major"), vec![0., 0., 0., 1., 0., 0., 0.]);
+    assert_eq!(shifts("bb minor"), vec![0., -1., -1., 0., -1., -1., -1.]);
+    assert_eq!(shifts("d dorian"), vec![0.; 7]);
+    assert!(KeySignature::parse("h major").is_none() && KeySignature::parse("c bebop").is_none());
+}
+
+#[test]
+fn written_accidentals_last_until_the_end_of_the_bar() {
+    let g_major = KeySignature::parse("g major").unwrap();
+    let notes = tuned_bar("f4 1/4, f4 natural 1/4, f4 1/4, f5 1/4", &Tuning::default(), &Transform::default(), &g_major);
+    let expected = [freqs(&bar("f4 sharp 1/4")), freqs(&bar("f4 1/4")), freqs(&bar("f4 1/4")), freqs(&bar("f5 sharp 1/4"))].concat();
+    assert_eq!(freqs(&notes), expected);
+}
+
+#[test]
+fn numeric_variants_replace_the_key() {
+    let g_major = KeySignature::parse("g major").unwrap();
+    let notes = tuned_bar("f4 1 1/4, f4 -1 1/4, f4 1/4, g4 1 1/4", &Tuning::default(), &Transform::default(), &g_major);
+    // F sharp, not G, then F flat, which stays until the end of the bar
+    let expected = [freqs(&bar("f4 sharp 1/4")), freqs(&bar("e4 1/4")), freqs(&bar("e4 1/4")), freqs(&bar("g4 sharp 1/4"))].concat();
+    assert_eq!(freqs(&notes), expected);
+}
+
+fn section(length: Option<i64>, lines: &[&str]) -> Section<'static> {
+    let mut section = Section::new(length);
+    for raw in lines {
+        section.push(line(), raw.to_string());
+    }
+    section
+}
+
+#[test]
+fn arrangements_repeat_their_sections() {
+    let order = parse_arrangement("intro verse x2 chorus[transpose: 2]", line());
+    let names: Vec<(&str, &str)> = order.iter().map(|(name, settings)| (name.as_str(), settings.as_str())).collect();
+    assert_eq!(names, vec![("intro", ""), ("verse", ""), ("verse", ""), ("chorus", "transpose: 2")]);
+}
+
+#[test]
+fn voltas_give_each_pass_its_length() {
+    let mut sections = HashMap::new();
+    // the second ending is a bar longer than the first one
+    let verse = section(None, &["1, c4 0 1", "volta 1:", "2, d4 0 1", "end volta", "volta 2:", "2, e4 0 1", "3, f4 0 1", "end volta"]);
+    assert_eq!((verse.bars(1), verse.bars(2)), (2, 3));
+    sections.insert("verse".to_string(), verse);
+    // 2 bars of rest after the written one
+    let bridge = section(Some(3), &["Notesheet: SineWave", "1, g4 0 1"]);
+    assert_eq!(bridge.bars(1), 3);
+    sections.insert("bridge".to_string(), bridge);
+    let order = parse_arrangement("verse bridge[transpose: 2] verse", line());
+    let played: Vec<String> = arrange(&sections, &order, 1, line()).into_iter().map(|(_, raw)| raw).collect();
+    assert_eq!(
+        played,
+        vec!["1, c4 0 1", "2, d4 0 1", "Notesheet: SineWave, transpose: 2", "3, g4 0 1", "6, c4 0 1", "7, e4 0 1", "8, f4 0 1"]
+    );
+}
+
+// an instrument of the library user: every note is a block of its loudness
+struct Block {
+    gain: f64,
+}
+
+impl Instrument for Block {
+    fn new() -> Block {
+        Block { gain: 1. }
+    }
+    fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        match param.0.as_str() {
+            "gain" => self.gain = param.1.parse::<f64>().map_err(|_| "gain should be a number")?,
+            _ => return Err("setting an unexisting parameter"),
+        }
+        Ok(())
+    }
+    fn value(&self, name: &str) -> Option<f64> {
+        (name == "gain").then_some(self.gain)
+    }
+    fn single_note(&self, note: &Note) -> Track {
+        let mut track = Track::new();
+        track.track = vec![self.gain * note.loud(); Track::time_to_sample_index(note.leng())];
+        track.starting_sample_index = Track::time_to_sample_index(note.time());
+        track
+    }
+}
+
+#[test]
+fn registered_instruments_are_created_by_name() {
+    let mut registry = Registry::new();
+    registry.register("Block", || Box::new(Block::new()));
+    assert!(registry.contains("block") && registry.contains("SineWave"));
+    let mut block = registry.create(" BLOCK ").unwrap();
+    assert_eq!(block.from_parameters(vec![&setting("gain", "0.5")]), Ok(1));
+    assert!(block.update(&setting("volume", "1")).is_err());
+    let track = block.track_from_notes(&bar("a4 1/4, r 1/4, a4 1/4"));
+    assert_eq!(track.ending_sample_index(), Track::time_to_sample_index(1.5));
+    assert_eq!(track.sample_in_global(1), 0.5);
+    assert_eq!(track.sample_in_global(Track::time_to_sample_index(0.75)), 0.);
+}
+
+#[test]
+fn modulation_is_taken_at_the_start_of_every_note() {
+    let mut modulation = Modulation::default();
+    modulation.update(&setting("lfo1", "square 1")).unwrap();
+    modulation.update(&setting("mod", "lfo1 -> gain 0.5")).unwrap();
+    let mut block = Block::new();
+    let notes = vec![Note::new(440., 1., 0.), Note::new(440., 0.2, 2.6)];
+    let track = modulation.play(&mut block, &notes, &TempoMap::default(), &Automation::default()).unwrap();
+    let at = |t: f64| track.sample_in_global(Track::time_to_sample_index(t));
+    // the square is up for the first half of every second, the held note keeps it after that
+    assert_eq!(at(0.1), 1.5);
+    assert_eq!(at(0.9), 1.5);
+    assert_eq!(at(2.7), 0.5);
+    modulation.update(&setting("mod", "lfo2 -> gain 0.5")).unwrap();
+    assert!(modulation.play(&mut block, &notes, &TempoMap::default(), &Automation::default()).is_err());
+}
+
+#[test]
+fn unknown_instruments_are_not_created() {
+    let registry = Registry::default();
+    assert!(registry.create("theremin").is_none());
+    assert!(!registry.contains("theremin"));
+}
+
+// directory of a test with its files written in it
+fn files(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("wav_gen_{test}_{}", std::process::id()));
+    for (name, text) in files {
+        let path = dir.join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, text).unwrap();
+    }
+    dir
+}
+
+#[test]
+fn includes_are_read_in_place() {
+    let dir = files(
+        "include",
+        &[
+            ("song.wavg", "Name: Song\nInclude: parts/verse.wavg\nrecord\nend\n"),
+            // paths are relative to the including file, included files end at their "end" line
+            ("parts/verse.wavg", "Notesheet: SineWave\nInclude: bass.wavg\n1, c4 0 1\nend\n1, d4 0 1\n"),
+            ("parts/bass.wavg", "1, c2 0 1\n"),
+        ],
+    );
+    let source = Source::read(&dir.join("song.wavg")).unwrap();
+    let lines: Vec<String> = source
+        .lines()
+        .map(|(line, raw)| format!("{} {}: {raw}", line.file.file_name().unwrap().to_string_lossy(), line.number))
+        .collect();
+    assert_eq!(
+        lines,
+        vec![
+            "song.wavg 1: Name: Song",
+            "verse.wavg 1: Notesheet: SineWave",
+            "bass.wavg 1: 1, c2 0 1",
+            "verse.wavg 3: 1, c4 0 1",
+            "song.wavg 3: record",
+            "song.wavg 4: end"
+        ]
+    );
+}
+
+#[test]
+#[should_panic(expected = "include cycle")]
+fn include_cycles_are_found() {
+    let dir = files("cycle", &[("a.wavg", "Include: b.wavg\nend\n"), ("b.wavg", "Include: ./a.wavg\n")]);
+    let _ = Source::read(&dir.join("a.wavg"));
+}
+
+#[test]
+fn presets_are_loaded_from_files() {
+    let dir = files("preset_file", &[("song.wavgp", "# pads\n\nDark_Pad: SineWave, volume: 0.4, freq_mod: 0.5\nquiet: sinewave, volume: 0.1\n")]);
+    let mut presets = PresetLibrary::new();
+    presets.load_file(&dir.join("song.wavgp")).unwrap();
+    let pars = |list: &[(&str, &str)]| -> Vec<(String, String)> {
+        list.iter().map(|(key, value)| setting(key, value)).collect()
+    };
+    assert_eq!(presets.get("SineWave", "dark_pad"), Some(&pars(&[("volume", "0.4"), ("freq_mod", "0.5")])));
+    assert_eq!(presets.get("sinewave", "QUIET"), Some(&pars(&[("volume", "0.1")])));
+    assert_eq!(presets.get("simpledrum", "quiet"), None);
+    assert!(presets.load_file(&dir.join("missing.wavgp")).is_err());
+}
+
+#[test]
+fn notesheet_parameters_override_presets() {
+    let mut presets = PresetLibrary::new();
+    presets.add("SineWave", "pad", vec![setting("volume", "0.6"), setting("vibrato", "0.1")]);
+    let pars = presets.expand("sinewave", vec![setting("volume", "0.2"), setting("preset", "pad"), setting("channel", "lead")]).unwrap();
+    // the preset goes first, so that the volume written in the notesheet line is the one that stays
+    assert_eq!(pars, vec![setting("volume", "0.6"), setting("vibrato", "0.1"), setting("volume", "0.2"), setting("channel", "lead")]);
+    assert!(presets.expand("simpledrum", vec![setting("preset", "pad")]).is_err());
+}
+
+#[test]
+#[should_panic(expected = "can't read the presets")]
+fn missing_presets_name_their_line() {
+    let dir = files("presets", &[("song.wavg", "Name: Song\nPresets: missing.wavgp\nend\n")]);
+    let _ = crate::director(&dir.join("song.wavg").into_os_string());
+}
+
+#[test]
+#[should_panic(expected = "key should be something like g major, bb minor or d dorian, at line: 2 of")]
+fn wrong_notesheet_settings_name_their_line() {
+    let dir = files("settings", &[("song.wavg", "Name: Song\nNotesheet: SineWave, key: h major\n1, c4 0 1/4\nrecord\nend\n")]);
+    let _ = crate::director(&dir.join("song.wavg").into_os_string());
+}
+
+#[test]
+#[should_panic(expected = "Failed parsing note: invalid digit found in string in \"x\" at line 3 of")]
+fn wrong_notes_name_their_line() {
+    let dir = files("notes", &[("song.wavg", "Name: Song\nNotesheet: SineWave\n1, c4 0 1/x\nrecord\nend\n")]);
+    let _ = crate::director(&dir.join("song.wavg").into_os_string());
+}
+
+// a single click a few samples in, mixing skips the very first sample of a track
+fn impulse(length: usize) -> Track {
+    let mut samples = vec![0.; length];
+    samples[10] = 1.;
+    Track::from(samples)
+}
+
+fn effect(setting: &str) -> Box<dyn Effect> {
+    parse_effect(setting).unwrap().unwrap()
+}
+
+#[test]
+fn delays_echo_quieter_and_quieter() {
+    let mut track = impulse(100);
+    effect("delay 0.01 0.5").apply(&mut track);
+    // 441 samples apart, the tail lasts until the echoes are 60 db down
+    assert_eq!(track.track.len(), 100 + (441. * 0.001_f64.ln() / 0.5_f64.ln()) as usize);
+    let echoes: Vec<f64> = [10, 451, 892, 1333].iter().map(|i| track.track[*i]).collect();
+    assert_eq!(echoes, vec![0., 1., 0.5, 0.25]);
+    // half of the dry sound stays
+    let mut track = impulse(100);
+    effect("delay 0.01 0.5 0.5").apply(&mut track);
+    assert_eq!((track.track[10], track.track[451]), (0.5, 0.5));
+    assert!(parse_effect("delay 0.01 1").unwrap().is_err());
+}
+
+#[test]
+fn reverbs_ring_out() {
+    let tail = |size: &str| {
+        let mut track = impulse(100);
+        effect(&format!("reverb {size}")).apply(&mut track);
+        track.track
+    };
+    let (small, hall) = (tail("0"), tail("1"));
+    assert!(hall.len() > small.len());
+    let energy = |samples: &[f64]| samples.iter().map(|sample| sample * sample).sum::<f64>();
+    // the room answers after the click and dies out
+    assert!(energy(&hall[..hall.len() / 10]) > 0.);
+    assert!(energy(&hall[hall.len() * 9 / 10..]) < energy(&hall[..hall.len() / 10]) * 1e-3);
+    assert!(parse_effect("reverb 2").unwrap().is_err());
+}
+
+#[test]
+fn filters_split_the_spectrum() {
+    let sine = |freq: f64| Track::from((0..4410).map(|i| (2. * std::f64::consts::PI * freq * i as f64 / 44100.).sin()).collect::<Vec<f64>>());
+    let peak = |setting: &str, freq: f64| {
+        let mut track = sine(freq);
+        effect(setting).apply(&mut track);
+        track.track[2205..].iter().fold(0_f64, |peak, sample| peak.max(sample.abs()))
+    };
+    assert!(peak("lowpass 200", 50.) > 0.9 && peak("lowpass 200", 5000.) < 0.1);
+    assert!(peak("highpass 2000", 50.) < 0.1 && peak("highpass 2000", 10000.) > 0.7);
+    // the highpass is what the lowpass takes away
+    let (mut low, mut high) = (sine(1000.), sine(1000.));
+    effect("lowpass 1000").apply(&mut low);
+    effect("highpass 1000").apply(&mut high);
+    let original = sine(1000.);
+    assert!((0..4410).all(|i| (low.track[i] + high.track[i] - original.track[i]).abs() < 1e-9));
+}
+
+#[test]
+fn buses_play_what_is_sent_to_them() {
+    let mut mixer = Mixer::default();
+    mixer.set("bus echo: delay 0.01 0.5").unwrap();
+    mixer.set("bus room: reverb 1").unwrap();
+    mixer.set("lead: send echo -6").unwrap();
+    // the only sender of the room is muted
+    mixer.set("pad: mute, send room 0").unwrap();
+    mixer.add("lead", impulse(100));
+    mixer.add("pad", impulse(100));
+    let mixdown = mixer.mixdown(&[], &[], true).unwrap();
+    let stems: Vec<&str> = mixdown.stems.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(stems, vec!["lead", "echo"]);
+    let song = &mixdown.song[0];
+    assert_eq!(song.track.len(), 100 + (441. * 0.001_f64.ln() / 0.5_f64.ln()) as usize);
+    // the click and its first echo, 6 db down
+    assert_eq!(song.sample_in_global(10), 1.);
+    assert!((song.sample_in_global(451) - amplitude(-6.)).abs() < 1e-12);
+    let mut mixer = Mixer::default();
+    mixer.set("bus echo: delay 0.01 0.5").unwrap();
+    mixer.set("bus room: reverb 1, send echo 0").unwrap();
+    assert!(mixer.mixdown(&[], &[], false).is_err());
+}
+
+#[test]
+fn channels_have_their_gain_and_pan() {
+    let mut mixer = Mixer::default();
+    mixer.set("lead: gain -6, pan -1").unwrap();
+    mixer.set("bass: pan 1").unwrap();
+    mixer.set("pad: gain 3").unwrap();
+    mixer.add("lead", impulse(100));
+    mixer.add("bass", impulse(100));
+    mixer.add("pad", impulse(100));
+    let mixdown = mixer.mixdown(&[], &[], true).unwrap();
+    assert_eq!(mixdown.song.len(), 2);
+    let side = |stem: &str, side: usize| {
+        let (_, tracks) = mixdown.stems.iter().find(|(name, _)| name == stem).unwrap();
+        tracks[side].sample_in_global(10)
+    };
+    assert!((side("lead", 0) - amplitude(-6.)).abs() < 1e-12);
+    assert!(side("lead", 1).abs() < 1e-12);
+    assert!(side("bass", 0).abs() < 1e-12);
+    assert!((side("bass", 1) - 1.).abs() < 1e-12);
+    // the center is half the power on either side
+    let center = amplitude(3.) * std::f64::consts::FRAC_1_SQRT_2;
+    assert!((side("pad", 0) - center).abs() < 1e-12);
+    assert!((side("pad", 1) - center).abs() < 1e-12);
+    let left = amplitude(-6.) + center;
+    assert!((mixdown.song[0].sample_in_global(10) - left).abs() < 1e-12);
+}
+
+#[test]
+fn solo_and_mute_leave_channels_out() {
+    let played = |settings: &[&str], solo: &[&str], mute: &[&str]| {
+        let mut mixer = Mixer::default();
+        for setting in settings {
+            mixer.set(setting).unwrap();
+        }
+        for channel in ["lead", "bass", "drums"] {
+            mixer.add(channel, impulse(100));
+        }
+        let solo: Vec<String> = solo.iter().map(|name| name.to_string()).collect();
+        let mute: Vec<String> = mute.iter().map(|name| name.to_string()).collect();
+        let mixdown = mixer.mixdown(&solo, &mute, true).unwrap();
+        mixdown.stems.into_iter().map(|(name, _)| name).collect::<Vec<String>>()
+    };
+    assert_eq!(played(&[], &[], &[]), vec!["lead", "bass", "drums"]);
+    assert_eq!(played(&["bass: mute"], &[], &[]), vec!["lead", "drums"]);
+    assert_eq!(played(&[], &[], &["lead", "drums"]), vec!["bass"]);
+    assert_eq!(played(&["drums: solo"], &[], &[]), vec!["drums"]);
+    assert_eq!(played(&["drums: solo"], &["lead"], &[]), vec!["lead", "drums"]);
+    assert_eq!(played(&[], &["bass"], &["bass"]), Vec::<String>::new());
+}
+
+#[test]
+fn stems_add_up_to_the_song() {
+    let mut mixer = Mixer::default();
+    mixer.set("bus echo: delay 0.01 0.5").unwrap();
+    mixer.set("lead: gain -3, pan 0.5, send echo -6").unwrap();
+    mixer.set("bass: pan -0.2").unwrap();
+    let mut late = Track::from(vec![0.5; 300]);
+    late.starting_sample_index = 200;
+    mixer.add("lead", impulse(100));
+    mixer.add("bass", late);
+    let mut mixdown = mixer.mixdown(&[], &[], true).unwrap();
+    crate::before_master(&mut mixdown, &Automation::default(), &TempoMap::default());
+    let ending = mixdown.song[0].ending_sample_index();
+    assert_eq!(mixdown.stems.len(), 3);
+    for (side, song) in mixdown.song.iter().enumerate() {
+        assert_eq!(song.starting_sample_index, 0);
+        assert_eq!(song.ending_sample_index(), ending);
+        for (_, stem) in &mixdown.stems {
+            assert_eq!(stem[side].starting_sample_index, 0);
+            assert_eq!(stem[side].ending_sample_index(), ending);
+        }
+        for i in 0..ending {
+            let stems: f64 = mixdown.stems.iter().map(|(_, stem)| stem[side].track[i]).sum();
+            assert!((song.track[i] - stems).abs() < 1e-12);
+        }
+    }
+}
+
+#[test]
+fn unknown_command_line_channels_are_errors() {
+    let dir = files("solo", &[("song.wavg", "Name: Song\nNotesheet: SineWave, channel: lead, timing: sequential\n1, c4 1/4\nrecord\nend\n")]);
+    let song = dir.join("song.wavg").into_os_string();
+    for options in [
+        crate::Options { solo: vec!["nosuch".to_string()], ..Default::default() },
+        crate::Options { mute: vec!["nosuch".to_string()], ..Default::default() },
+    ] {
+        let error = crate::director_with_options(&song, &Registry::default(), &options).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("nosuch"));
+    }
+}
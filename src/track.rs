@@ -3,7 +3,28 @@ pub const DESIRED_SAMPLE_RATE: u32 = 44100;
 pub struct Track {
     pub track: Vec<f64>,
     pub starting_sample_index: usize,
-    pub loudness: f64,
+    pub loudness: Gain,
+}
+
+// Gain of a track, either the same all along or changing with time, see automation.rs
+#[derive(Clone)]
+pub enum Gain {
+    Constant(f64),
+    // gain at every sample since the beginning of the song, the last one holds until the end
+    Curve(Vec<f64>),
+}
+
+impl Gain {
+    // gain at the absolute sample index
+    pub fn at(&self, i: usize) -> f64 {
+        match self {
+            Gain::Constant(gain) => *gain,
+            Gain::Curve(gains) => match gains.get(i) {
+                Some(gain) => *gain,
+                None => gains.last().copied().unwrap_or(1.),
+            },
+        }
+    }
 }
 
 impl From<Vec<f64>> for Track {
@@ -11,7 +32,7 @@ impl From<Vec<f64>> for Track {
         Track {
             track: thing,
             starting_sample_index: 0,
-            loudness: 1.,
+            loudness: Gain::Constant(1.),
         }
     }
 }
@@ -25,8 +46,8 @@ impl From<Track> for Vec<f64> {
 impl From<Vec<Track>> for Track {
     fn from(mut thing: Vec<Track>) -> Track {
         let mut temp: Track = Track::new();
-        for i in 0..thing.len() {
-            temp = temp.mix(&mut thing[i]);
+        for part in &mut thing {
+            temp = temp.mix(part);
         }
         temp
     }
@@ -38,25 +59,29 @@ impl From<Track> for Vec<(f64, f64)> {
             "This function (from(Track) -> Vec<(f64, f64)>) was called somewhere. Consider reworking it to work with Vec<f64> instead"
         );
         let mut wave: Vec<(f64, f64)> = Vec::new();
-        let mut i: usize = 0;
-        for sample in thing.track {
+        for (i, sample) in thing.track.into_iter().enumerate() {
             wave.push((
                 sample,
                 (thing.starting_sample_index + i) as f64 / DESIRED_SAMPLE_RATE as f64,
             ));
-            i += 1;
         }
         wave
     }
 }
 
+impl Default for Track {
+    fn default() -> Track {
+        Track::new()
+    }
+}
+
 impl Track {
     pub fn new() -> Track {
         let track: Vec<f64> = Vec::new();
         Track {
             track,
             starting_sample_index: 0,
-            loudness: 1.,
+            loudness: Gain::Constant(1.),
         }
     }
     pub fn length(&self) -> f64 {
@@ -76,6 +101,13 @@ impl Track {
     pub fn start_with_silence(&mut self) {
         let beginning = vec![0.; self.starting_sample_index];
         self.track = [beginning, self.track.clone()].concat();
+        self.starting_sample_index = 0;
+    }
+    // silence added at the end, so that the track lasts until the absolute sample index
+    pub fn end_with_silence(&mut self, ending_sample_index: usize) {
+        if ending_sample_index > self.ending_sample_index() {
+            self.track.resize(ending_sample_index - self.starting_sample_index, 0.);
+        }
     }
     pub fn sample_in_global(&self, i: usize) -> f64 {
         if i > self.starting_sample_index && i - self.starting_sample_index < self.track.len() {
@@ -85,7 +117,7 @@ impl Track {
         }
     }
     pub fn get_value_at_t(&self, sample_time: f64) -> f64 {
-        if self.track.len() == 0 {
+        if self.track.is_empty() {
             return 0.;
         }
         let mut sampling_sample = (sample_time * DESIRED_SAMPLE_RATE as f64).floor() as usize;
@@ -112,14 +144,14 @@ impl Track {
             Vec::with_capacity(mix_ending_sample_index - mix_starting_sample_index);
         for i in mix_starting_sample_index..mix_ending_sample_index {
             mix.push(
-                self.sample_in_global(i) * self.loudness
-                    + another.sample_in_global(i) * another.loudness,
+                self.sample_in_global(i) * self.loudness.at(i)
+                    + another.sample_in_global(i) * another.loudness.at(i),
             );
         }
         Track {
             track: mix,
             starting_sample_index: mix_starting_sample_index,
-            loudness: 1.,
+            loudness: Gain::Constant(1.),
         }
     }
 
@@ -134,20 +166,26 @@ impl Track {
         self.starting_sample_index = t0_sample;
     }
 
-    // makes everything to be within [-1; 1] range
-    pub fn normalize(&mut self) {
+    // highest absolute value of a sample
+    pub fn peak(&self) -> f64 {
         let mut max_amp: f64 = 0.;
         for sample in &self.track {
             if max_amp < sample.abs() {
                 max_amp = sample.abs();
             }
         }
-        self.loudness = 1. / max_amp;
+        max_amp
+    }
+
+    // makes everything to be within [-1; 1] range, peak is the loudest sample of the tracks normalized together.
+    // Silence (everything muted, or played at v0) stays as it is
+    pub fn normalize(&mut self, peak: f64) {
+        self.loudness = Gain::Constant(if peak > 0. { 1. / peak } else { 1. });
     }
 
     pub fn apply_loudness(&mut self) {
-        for sample in &mut self.track {
-            *sample *= self.loudness;
+        for (i, sample) in self.track.iter_mut().enumerate() {
+            *sample *= self.loudness.at(self.starting_sample_index + i);
         }
     }
 }
@@ -170,7 +208,7 @@ impl LinearFadeInOut {
     }
     pub fn in_l(length: f64) -> Self {
         LinearFadeInOut {
-            length: length,
+            length,
             is_out: false,
         }
     }
@@ -182,7 +220,7 @@ impl LinearFadeInOut {
     }
     pub fn out_l(length: f64) -> Self {
         LinearFadeInOut {
-            length: length,
+            length,
             is_out: true,
         }
     }
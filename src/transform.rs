@@ -0,0 +1,134 @@
+// Transforms change the notes of a notesheet as a whole when it is recorded, so that a motif can be reused.
+// Sections get them in the arrangement: "Arrange: verse verse[transpose: 5, retrograde: true]",
+// they are added to every notesheet of the section and replace the ones written there.
+
+// settings, global or per notesheet:
+// transpose: semitones up (or down, when negative), fractions are microtones
+// octave: octaves up or down
+// invert: tone the melody is mirrored around, such as "e4"
+// retrograde: true plays the notes backwards, bends go the other way round too
+// time_scale: 2 plays twice as slow (augmentation), 1/2 twice as fast (diminution),
+// the notesheet is stretched from its first note
+
+// Tones are moved in steps of the tuning before they are tuned, so that a transposed melody stays in a scale
+// that isn't 12-TET. Retrograde and time_scale work in 1/4th, so that the notes follow the tempo map.
+
+use crate::harmonics::{parse_pitch, Tone};
+use crate::instruments::Note;
+use crate::tempo::TempoMap;
+
+#[derive(Clone)]
+pub struct Transform {
+    transpose: f64,
+    octave: i64,
+    invert: Option<Tone>,
+    retrograde: bool,
+    time_scale: f64,
+}
+
+impl Default for Transform {
+    fn default() -> Transform {
+        Transform {
+            transpose: 0.,
+            octave: 0,
+            invert: None,
+            retrograde: false,
+            time_scale: 1.,
+        }
+    }
+}
+
+impl Transform {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        let value = param.1.trim();
+        match param.0.as_str() {
+            "transpose" => {
+                self.transpose = match value.parse::<f64>() {
+                    Ok(val) => val,
+                    Err(_) => return Err("transpose should be a number of semitones, such as +5 or -2"),
+                }
+            }
+            "octave" => {
+                self.octave = match value.parse::<i64>() {
+                    Ok(val) => val,
+                    Err(_) => return Err("octave should be a whole number of octaves, such as -1"),
+                }
+            }
+            "invert" => {
+                self.invert = match value {
+                    "none" | "false" => None,
+                    tone => match parse_pitch(tone) {
+                        Ok(tone) => Some(tone),
+                        Err(_) => return Err("invert should be a tone to mirror around, such as e4 or f4 sharp"),
+                    },
+                }
+            }
+            "retrograde" => {
+                self.retrograde = match value {
+                    "true" | "yes" | "on" => true,
+                    "false" | "no" | "off" => false,
+                    _ => return Err("retrograde should be true or false"),
+                }
+            }
+            "time_scale" => {
+                let scale = match value.split_once('/') {
+                    Some((num, den)) => num.parse::<f64>().ok().zip(den.parse::<f64>().ok()).map(|(n, d)| n / d),
+                    None => value.parse::<f64>().ok(),
+                };
+                self.time_scale = match scale {
+                    Some(val) if val > 0. && val.is_finite() => val,
+                    _ => return Err("time_scale should be a positive number or fraction, such as 2 or 1/2"),
+                }
+            }
+            _ => return Err("setting an unexisting parameter"),
+        }
+        Ok(())
+    }
+
+    // tone of a note as it is written, transposed and mirrored around the axis, before it is tuned
+    pub fn tone(&self, tone: Tone) -> Tone {
+        let axis = self.invert.map(|axis| axis.semitones());
+        let shift = self.transpose + 12. * self.octave as f64;
+        let moved = |semitones: f64| {
+            shift
+                + match axis {
+                    Some(axis) => 2. * axis - semitones,
+                    None => semitones,
+                }
+        };
+        match tone {
+            Tone::Semitones(semitones) => Tone::Semitones(moved(semitones)),
+            // exact frequencies are moved by equal tempered semitones
+            Tone::Hz(_) => Tone::Hz(440. * (moved(tone.semitones()) / 12.).exp2()),
+        }
+    }
+
+    // notes of the notesheet played backwards and stretched, in 1/4th
+    pub fn apply(&self, notes: Vec<Note>, tempo: &TempoMap) -> Vec<Note> {
+        if !self.retrograde && self.time_scale == 1. {
+            return notes;
+        }
+        let span = |note: &Note| (tempo.position(note.time()), tempo.position(note.time() + note.leng()));
+        let start = notes.iter().map(|note| span(note).0).fold(f64::INFINITY, f64::min);
+        let end = notes.iter().map(|note| span(note).1).fold(f64::NEG_INFINITY, f64::max);
+        notes
+            .into_iter()
+            .map(|mut note| {
+                let (from, until) = span(&note);
+                let (from, until) = if self.retrograde {
+                    note.reverse_glide();
+                    (start + end - until, start + end - from)
+                } else {
+                    (from, until)
+                };
+                let time = tempo.seconds(start + (from - start) * self.time_scale);
+                let leng = tempo.seconds(start + (until - start) * self.time_scale) - time;
+                if note.leng() > 0. {
+                    note.stretch(leng / note.leng());
+                }
+                note.set_time(time);
+                note
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,214 @@
+// Tuning turns a distance in semitones from A4 into a frequency.
+// Equal temperaments divide the octave evenly, the others are built from frequency ratios
+// relative to a tonic, so the same tone sounds slightly different depending on the tonic.
+
+// global settings:
+// tuning: 12tet (default), just, pythagorean, meantone (quarter-comma), or any N-EDO such as 19edo
+// a4: reference frequency of A4 in Hz, 440 by default
+// tonic: tone the ratios of non-equal tunings are counted from, such as c (default), f# or bb
+// scale, keyboard_map: Scala files, see scala.rs
+
+use crate::scala::{KeyboardMap, Scale};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Temperament {
+    // the octave is divided into this many equal steps
+    Equal(u32),
+    // 5-limit just intonation
+    Just,
+    // built from pure fifths
+    Pythagorean,
+    // quarter-comma meantone, pure major thirds
+    Meantone,
+    // loaded from a Scala file
+    Scala,
+}
+
+#[derive(Clone)]
+pub struct Tuning {
+    pub temperament: Temperament,
+    // frequency of A4 in Hz
+    pub a4: f64,
+    // distance in semitones from C of the tonic
+    pub tonic: i64,
+    scale: Option<Scale>,
+    keyboard: Option<KeyboardMap>,
+}
+
+const JUST_RATIOS: [f64; 12] = [
+    1.,
+    16. / 15.,
+    9. / 8.,
+    6. / 5.,
+    5. / 4.,
+    4. / 3.,
+    45. / 32.,
+    3. / 2.,
+    8. / 5.,
+    5. / 3.,
+    9. / 5.,
+    15. / 8.,
+];
+
+const PYTHAGOREAN_RATIOS: [f64; 12] = [
+    1.,
+    256. / 243.,
+    9. / 8.,
+    32. / 27.,
+    81. / 64.,
+    4. / 3.,
+    729. / 512.,
+    3. / 2.,
+    128. / 81.,
+    27. / 16.,
+    16. / 9.,
+    243. / 128.,
+];
+
+// distance in semitones from C of a tone name without octave: "c", "f#", "bb", "e flat"
+pub fn parse_pitch_class(s: &str) -> Option<i64> {
+    let s = s.trim();
+    let base = match s.get(0..1)? {
+        "c" => 0,
+        "d" => 2,
+        "e" => 4,
+        "f" => 5,
+        "g" => 7,
+        "a" => 9,
+        "b" => 11,
+        _ => return None,
+    };
+    let shift = match s[1..].trim() {
+        "" => 0,
+        "#" | "sharp" => 1,
+        "b" | "flat" => -1,
+        _ => return None,
+    };
+    Some(base + shift)
+}
+
+impl Default for Tuning {
+    fn default() -> Tuning {
+        Tuning {
+            temperament: Temperament::Equal(12),
+            a4: 440.,
+            tonic: 0,
+            scale: None,
+            keyboard: None,
+        }
+    }
+}
+
+impl Tuning {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        match param.0.as_str() {
+            "tuning" => {
+                self.temperament = match param.1.as_str() {
+                    "12tet" | "equal" => Temperament::Equal(12),
+                    "just" => Temperament::Just,
+                    "pythagorean" => Temperament::Pythagorean,
+                    "meantone" => Temperament::Meantone,
+                    edo => match edo.strip_suffix("edo").map(|n| n.trim_end_matches('-').parse::<u32>()) {
+                        Some(Ok(n)) if n > 0 => Temperament::Equal(n),
+                        _ => return Err("unknown tuning, try 12tet, just, pythagorean, meantone or N-EDO, such as 19edo"),
+                    },
+                }
+            }
+            "a4" => {
+                self.a4 = match param.1.parse::<f64>() {
+                    Ok(val) if val > 0. => val,
+                    _ => return Err("a4 should be a positive frequency in Hz"),
+                }
+            }
+            "tonic" => {
+                self.tonic = match parse_pitch_class(&param.1) {
+                    Some(val) => val,
+                    None => return Err("tonic should be a tone name, such as c, f# or bb"),
+                }
+            }
+            _ => return Err("setting an unexisting parameter"),
+        }
+        Ok(())
+    }
+
+    pub fn set_scale(&mut self, scale: Scale) {
+        self.temperament = Temperament::Scala;
+        self.scale = Some(scale);
+    }
+    pub fn set_keyboard_map(&mut self, keyboard: KeyboardMap) {
+        self.keyboard = Some(keyboard);
+    }
+
+    // frequency of the tone that is this many semitones away from A4.
+    // Fractions of a semitone are added on top of the closest tone of the tuning
+    pub fn frequency(&self, semitones: f64) -> f64 {
+        if self.temperament == Temperament::Equal(12) {
+            return self.a4 * (semitones / 12.).exp2();
+        }
+        let closest = semitones.round();
+        self.tone_frequency(closest as i64) * ((semitones - closest) / 12.).exp2()
+    }
+
+    fn tone_frequency(&self, semitones: i64) -> f64 {
+        let ratios = match self.temperament {
+            Temperament::Scala => return self.scala_frequency(semitones),
+            Temperament::Equal(12) => return self.a4 * (semitones as f64 / 12.).exp2(),
+            Temperament::Equal(n) => {
+                // tones are rounded to the closest step
+                let steps = (semitones as f64 * n as f64 / 12.).round();
+                return self.a4 * (steps / n as f64).exp2();
+            }
+            Temperament::Just => JUST_RATIOS,
+            Temperament::Pythagorean => PYTHAGOREAN_RATIOS,
+            Temperament::Meantone => meantone_ratios(),
+        };
+        // tonic closest to A4 from below, tuned as in 12tet
+        let mut tonic = (self.tonic - 9).rem_euclid(12);
+        if tonic > 0 {
+            tonic -= 12;
+        }
+        let tonic_freq = self.a4 * (tonic as f64 / 12.).exp2();
+        let from_tonic = semitones - tonic;
+        tonic_freq
+            * (from_tonic.div_euclid(12) as f64).exp2()
+            * ratios[from_tonic.rem_euclid(12) as usize]
+    }
+
+    // semitones are keys of a MIDI keyboard, A4 being key 69
+    fn scala_frequency(&self, semitones: i64) -> f64 {
+        let scale = match &self.scale {
+            Some(scale) => scale,
+            None => panic!("Scala tuning is used without a scale"),
+        };
+        let key = 69 + semitones;
+        // without a keyboard mapping degree 0 is the tonic of the 4th octave
+        let linear;
+        let keyboard = match &self.keyboard {
+            Some(keyboard) => keyboard,
+            None => {
+                linear = KeyboardMap::linear(60 + self.tonic, 69, self.a4);
+                &linear
+            }
+        };
+        match keyboard.frequency(scale, key) {
+            Some(freq) => freq,
+            None => panic!("Failed tuning note: key {key} is not mapped by the keyboard mapping"),
+        }
+    }
+}
+
+// quarter-comma meantone has fifths that are slightly flat, so that four of them make a pure major third.
+// Chromatic tones go from 3 fifths down (minor third) to 8 fifths up (augmented fifth)
+fn meantone_ratios() -> [f64; 12] {
+    let fifth = 5_f64.powf(0.25);
+    let mut ratios = [1.; 12];
+    for (degree, ratio) in ratios.iter_mut().enumerate() {
+        let mut fifths = (degree as i64 * 7).rem_euclid(12);
+        if fifths > 8 {
+            fifths -= 12;
+        }
+        let raw = fifth.powi(fifths as i32);
+        *ratio = raw / raw.log2().floor().exp2();
+    }
+    ratios
+}
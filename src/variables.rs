@@ -0,0 +1,244 @@
+// Variables give names to the values that are written again and again.
+// "let root = c3" and "const q = 1/4" are lines of their own, constants can't be changed later.
+// "$root" is replaced by the value anywhere on the following lines (names are case insensitive),
+// arithmetic is worked out in parentheses anywhere, "c4 0 ($q * 3)", and in the whole values of settings,
+// "volume: $base * 0.5". Fractions stay fractions, so they can be note lengths.
+
+use crate::source::Line;
+use std::collections::{HashMap, HashSet};
+
+#[derive(Default)]
+pub struct Variables {
+    values: HashMap<String, String>,
+    constants: HashSet<String>,
+}
+
+#[derive(Clone, Copy)]
+enum Number {
+    // numerator and denominator
+    Ratio(i64, i64),
+    Real(f64),
+}
+
+fn gcd(a: i64, b: i64) -> i64 {
+    if b == 0 {
+        a.abs().max(1)
+    } else {
+        gcd(b, a % b)
+    }
+}
+
+impl Number {
+    fn real(self) -> f64 {
+        match self {
+            Number::Ratio(num, den) => num as f64 / den as f64,
+            Number::Real(val) => val,
+        }
+    }
+
+    fn reduced(num: i64, den: i64) -> Number {
+        let divisor = gcd(num, den) * den.signum();
+        Number::Ratio(num / divisor, den / divisor)
+    }
+
+    fn apply(self, operator: char, other: Number) -> Option<Number> {
+        if let (Number::Ratio(a, b), Number::Ratio(c, d)) = (self, other) {
+            return match operator {
+                '+' => Some(Number::reduced(a * d + c * b, b * d)),
+                '-' => Some(Number::reduced(a * d - c * b, b * d)),
+                '*' => Some(Number::reduced(a * c, b * d)),
+                _ if c == 0 => None,
+                _ => Some(Number::reduced(a * d, b * c)),
+            };
+        }
+        let (a, b) = (self.real(), other.real());
+        Some(Number::Real(match operator {
+            '+' => a + b,
+            '-' => a - b,
+            '*' => a * b,
+            _ => a / b,
+        }))
+    }
+
+    fn to_text(self) -> String {
+        match self {
+            Number::Ratio(num, 1) => num.to_string(),
+            Number::Ratio(num, den) => format!("{num}/{den}"),
+            Number::Real(val) => val.to_string(),
+        }
+    }
+}
+
+// recursive descent over "1/4 * (2 + 0.5)", None if it's not arithmetic
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_spaces(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn sum(&mut self) -> Option<Number> {
+        let mut value = self.product()?;
+        loop {
+            self.skip_spaces();
+            match self.chars.next_if(|c| *c == '+' || *c == '-') {
+                Some(operator) => value = value.apply(operator, self.product()?)?,
+                None => return Some(value),
+            }
+        }
+    }
+
+    fn product(&mut self) -> Option<Number> {
+        let mut value = self.operand()?;
+        loop {
+            self.skip_spaces();
+            match self.chars.next_if(|c| *c == '*' || *c == '/') {
+                Some(operator) => value = value.apply(operator, self.operand()?)?,
+                None => return Some(value),
+            }
+        }
+    }
+
+    fn operand(&mut self) -> Option<Number> {
+        self.skip_spaces();
+        if self.chars.next_if_eq(&'-').is_some() {
+            return Number::Ratio(0, 1).apply('-', self.operand()?);
+        }
+        if self.chars.next_if_eq(&'(').is_some() {
+            let value = self.sum()?;
+            self.skip_spaces();
+            return self.chars.next_if_eq(&')').map(|_| value);
+        }
+        let mut literal = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+            literal.push(c);
+        }
+        if literal.contains('.') {
+            literal.parse::<f64>().ok().map(Number::Real)
+        } else {
+            literal.parse::<i64>().ok().map(|val| Number::Ratio(val, 1))
+        }
+    }
+}
+
+// value of the arithmetic, None if it is not arithmetic
+pub(crate) fn evaluate(s: &str) -> Option<String> {
+    let mut parser = Parser {
+        chars: s.chars().peekable(),
+    };
+    let value = parser.sum()?;
+    parser.skip_spaces();
+    if parser.chars.next().is_some() {
+        return None;
+    }
+    Some(value.to_text())
+}
+
+// numbers and fractions such as "6/8" are kept as they are written
+fn is_literal(s: &str) -> bool {
+    let s = s.trim();
+    s.parse::<f64>().is_ok()
+        || s.split_once('/').is_some_and(|(num, den)| num.parse::<i64>().is_ok() && den.parse::<u64>().is_ok())
+}
+
+fn is_name(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+impl Variables {
+    // "let name = value" or "const name = value", false for the other lines
+    pub fn define(&mut self, raw: &str, counter: Line) -> bool {
+        let (keyword, rest) = match raw.split_once(char::is_whitespace) {
+            Some(split) => split,
+            None => return false,
+        };
+        let keyword = keyword.to_ascii_lowercase();
+        if keyword != "let" && keyword != "const" {
+            return false;
+        }
+        let (name, value) = match rest.split_once('=') {
+            Some((name, value)) if is_name(name.trim()) => (name.trim().to_ascii_lowercase(), value),
+            _ => panic!("wavg synthax error: variables are defined as \"let name = value\" at line {counter}"),
+        };
+        if self.constants.contains(&name) {
+            panic!("wavg synthax error: {name} is a constant and can't be changed at line {counter}");
+        }
+        let value = Variables::expand_value(&self.substitute(value.trim(), counter));
+        if keyword == "const" {
+            self.constants.insert(name.clone());
+        }
+        self.values.insert(name, value);
+        true
+    }
+
+    // "$name" replaced by the value of the variable
+    fn substitute(&self, raw: &str, counter: Line) -> String {
+        let mut expanded = String::with_capacity(raw.len());
+        let mut rest = raw;
+        while let Some(dollar) = rest.find('$') {
+            expanded.push_str(&rest[..dollar]);
+            let tail = &rest[dollar + 1..];
+            let end = tail
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(tail.len());
+            let name = tail[..end].to_ascii_lowercase();
+            match self.values.get(&name) {
+                Some(value) => expanded.push_str(value),
+                None => panic!("wavg synthax error: variable ${name} is not defined at line {counter}"),
+            }
+            rest = &tail[end..];
+        }
+        expanded.push_str(rest);
+        expanded
+    }
+
+    // arithmetic in parentheses worked out, from the innermost ones
+    fn expand_parentheses(s: &str, counter: Line) -> String {
+        let mut s = s.to_string();
+        while let Some(close) = s.find(')') {
+            let open = match s[..close].rfind('(') {
+                Some(open) => open,
+                None => panic!("wavg synthax error: unbalanced parentheses at line {counter}"),
+            };
+            let value = match evaluate(&s[open + 1..close]) {
+                Some(value) => value,
+                None => panic!("wavg synthax error: can't work out \"{}\" at line {counter}", &s[open + 1..close]),
+            };
+            s.replace_range(open..=close, &value);
+        }
+        if s.contains('(') {
+            panic!("wavg synthax error: unbalanced parentheses at line {counter}");
+        }
+        s
+    }
+
+    // a whole value is worked out if it is arithmetic, other values stay as they are
+    fn expand_value(value: &str) -> String {
+        if is_literal(value) {
+            return value.to_string();
+        }
+        evaluate(value).unwrap_or(value.to_string())
+    }
+
+    // line with its variables replaced and its arithmetic worked out
+    pub fn expand(&self, raw: &str, counter: Line) -> String {
+        if !raw.contains(['$', '(', '+', '*', '/']) {
+            return raw.to_string();
+        }
+        let raw = self.substitute(raw, counter);
+        // bar lines only work out the parentheses, the notes are separated by spaces
+        if crate::arrange::bar_number(&raw).is_some() || !raw.contains(':') {
+            return Variables::expand_parentheses(&raw, counter);
+        }
+        raw.split(',')
+            .map(|entry| match entry.split_once(':') {
+                Some((key, value)) => format!("{key}: {}", Variables::expand_value(value.trim())),
+                None => entry.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
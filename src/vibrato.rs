@@ -0,0 +1,92 @@
+// Vibrato and pitch bends change the pitch of a note while it sounds, instruments follow them
+// with an oscillator whose phase adds up the changing frequency, see Note::phases.
+
+// settings, global or per notesheet:
+// vibrato: depth in semitones, 0 by default (no vibrato)
+// vibrato_rate: how many times a second the pitch goes up and down, 5 by default
+// vibrato_delay: seconds since the start of a note before the vibrato sets in, 0 by default
+
+// written after the length of a note:
+// vib:0.5 is the vibrato depth of this note only, "vib:0" plays it straight
+// bend:+2@1/4 reaches 2 semitones up a quarter note after the start of the note, evenly from its own pitch,
+// several bends make a curve: "g4 0 1/2 bend:0@1/8 bend:+2@1/4 bend:0@1/2". Without "@" the bend ends with the note.
+
+#[derive(Clone, Copy)]
+pub struct Vibrato {
+    // in semitones
+    pub depth: f64,
+    // in Hz
+    pub rate: f64,
+    // in seconds
+    pub delay: f64,
+}
+
+impl Default for Vibrato {
+    fn default() -> Vibrato {
+        Vibrato {
+            depth: 0.,
+            rate: 5.,
+            delay: 0.,
+        }
+    }
+}
+
+impl Vibrato {
+    pub fn update(&mut self, param: &(String, String)) -> Result<(), &'static str> {
+        let value = param.1.trim();
+        match param.0.as_str() {
+            "vibrato" => {
+                self.depth = match value.parse::<f64>() {
+                    Ok(val) if val >= 0. => val,
+                    _ => return Err("vibrato should be a depth in semitones, such as 0.3"),
+                }
+            }
+            "vibrato_rate" => {
+                self.rate = match value.parse::<f64>() {
+                    Ok(val) if val > 0. => val,
+                    _ => return Err("vibrato_rate should be a positive number of Hz, such as 5.5"),
+                }
+            }
+            "vibrato_delay" => {
+                self.delay = match value.parse::<f64>() {
+                    Ok(val) if val >= 0. => val,
+                    _ => return Err("vibrato_delay should be a time in seconds, such as 0.2"),
+                }
+            }
+            _ => return Err("setting an unexisting parameter"),
+        }
+        Ok(())
+    }
+
+    pub fn is_on(&self) -> bool {
+        self.depth > 0.
+    }
+
+    // ratio of the frequency at this many seconds since the start of the note
+    pub fn factor(&self, t: f64) -> f64 {
+        if !self.is_on() || t < self.delay {
+            return 1.;
+        }
+        let swing = (2. * std::f64::consts::PI * self.rate * (t - self.delay)).sin();
+        (self.depth * swing / 12.).exp2()
+    }
+}
+
+// "+2@1/4" as (semitones, time after the start of the note as a fraction of a whole note),
+// None when the time is not written
+pub fn parse_bend(s: &str) -> Option<(f64, Option<(i64, i64)>)> {
+    let (semitones, at) = match s.split_once('@') {
+        Some((semitones, at)) => (semitones, Some(at)),
+        None => (s, None),
+    };
+    let semitones = semitones.parse::<f64>().ok()?;
+    let at = match at.map(|at| at.split_once('/')) {
+        None => None,
+        Some(Some((num, den))) => match (num.parse::<i64>(), den.parse::<i64>()) {
+            (Ok(num), Ok(den)) if num >= 0 && den > 0 => Some((num, den)),
+            _ => return None,
+        },
+        Some(None) => return None,
+    };
+    Some((semitones, at))
+}
@@ -7,16 +7,30 @@ pub trait WaveData {
     fn get_bits_per_sample(&self) -> u16;
     fn get_encoding(&self) -> u16;
     fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
     fn to_byte_slice(&self) -> Vec<u8>;
     fn push_sample_data_from_f64(&mut self, data: f64);
-    fn generate_from_wave(&mut self, wave: &Vec<f64>, sample_rate: u32) {
-        let data_f64 = linerp_from_sample_rate(wave.to_vec(), DESIRED_SAMPLE_RATE, sample_rate);
-        for f_val in data_f64 {
-            assert!(
-                f_val.abs() <= 1.,
-                "wave amplitude is not within [-1, 1] range: {f_val}"
-            );
-            self.push_sample_data_from_f64(f_val);
+    fn generate_from_wave(&mut self, wave: &[f64], sample_rate: u32) {
+        self.generate_from_channels(&[wave], sample_rate);
+    }
+    // channels are resampled one by one and interleaved, as .wav files keep them
+    fn generate_from_channels(&mut self, channels: &[&[f64]], sample_rate: u32) {
+        let resampled: Vec<Vec<f64>> = channels
+            .iter()
+            .map(|wave| linerp_from_sample_rate(wave.to_vec(), DESIRED_SAMPLE_RATE, sample_rate))
+            .collect();
+        let length = resampled.iter().map(|wave| wave.len()).max().unwrap_or(0);
+        for i in 0..length {
+            for wave in &resampled {
+                let f_val = wave.get(i).copied().unwrap_or(0.);
+                assert!(
+                    f_val.abs() <= 1.,
+                    "wave amplitude is not within [-1, 1] range: {f_val}"
+                );
+                self.push_sample_data_from_f64(f_val);
+            }
         }
     }
     fn new() -> Self;
@@ -47,7 +61,7 @@ impl WaveData for Vec<u8> {
     }
     fn new() -> Self {
         let temp_vec: Vec<u8> = Vec::new();
-        return temp_vec;
+        temp_vec
     }
 }
 
@@ -78,7 +92,7 @@ impl WaveData for Vec<i16> {
     }
     fn new() -> Self {
         let temp_vec: Vec<i16> = Vec::new();
-        return temp_vec;
+        temp_vec
     }
 }
 
@@ -109,6 +123,6 @@ impl WaveData for Vec<f32> {
     }
     fn new() -> Self {
         let temp_vec: Vec<f32> = Vec::new();
-        return temp_vec;
+        temp_vec
     }
 }